@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::error::ZsyncError;
+
+/// Shared flag to stop a running scan or control file generation from
+/// another thread. Clones refer to the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Clears the flag, so work can be started again with the same token.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn check(&self) -> Result<(), ZsyncError> {
+        if self.is_cancelled() {
+            Err(ZsyncError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
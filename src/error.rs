@@ -0,0 +1,90 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::file_checksum::ChecksumAlgorithm;
+
+#[derive(Debug)]
+pub enum ZsyncError {
+    Io(io::Error),
+    MalformedHeader(String),
+    BadHeader {
+        name: String,
+        value: String,
+    },
+    InvalidBlocksize(usize),
+    InvalidHashLengths(String),
+    TruncatedChecksums {
+        expected: usize,
+        actual: usize,
+    },
+    TrailingChecksumData {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidUrl(String),
+    UnsafeFilename(String),
+    InvalidGzip(String),
+    Cancelled,
+    MissingChecksum,
+    ChecksumMismatch {
+        algorithm: ChecksumAlgorithm,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for ZsyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZsyncError::Io(e) => write!(f, "i/o error: {}", e),
+            ZsyncError::MalformedHeader(line) => write!(f, "malformed header line: {:?}", line),
+            ZsyncError::BadHeader { name, value } => {
+                write!(f, "invalid value {:?} for header {}", value, name)
+            }
+            ZsyncError::InvalidBlocksize(size) => write!(f, "invalid blocksize: {}", size),
+            ZsyncError::InvalidHashLengths(value) => {
+                write!(f, "invalid Hash-Lengths header: {:?}", value)
+            }
+            ZsyncError::TruncatedChecksums { expected, actual } => write!(
+                f,
+                "checksum section truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            ZsyncError::TrailingChecksumData { expected, actual } => write!(
+                f,
+                "checksum section too long: expected {} bytes, found {}",
+                expected, actual
+            ),
+            ZsyncError::InvalidUrl(url) => write!(f, "invalid url: {:?}", url),
+            ZsyncError::UnsafeFilename(name) => write!(f, "unsafe filename: {:?}", name),
+            ZsyncError::InvalidGzip(msg) => write!(f, "invalid gzip file: {}", msg),
+            ZsyncError::Cancelled => write!(f, "cancelled"),
+            ZsyncError::MissingChecksum => write!(f, "no whole-file checksum to verify against"),
+            ZsyncError::ChecksumMismatch {
+                algorithm,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} mismatch: expected {}, got {}",
+                algorithm, expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for ZsyncError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ZsyncError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ZsyncError {
+    fn from(e: io::Error) -> Self {
+        ZsyncError::Io(e)
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+use std::io::Read;
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::error::ZsyncError;
+
+/// Whole-file digest algorithms a control file can carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn header_name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Md5 => "MD5",
+            ChecksumAlgorithm::Sha1 => "SHA-1",
+            ChecksumAlgorithm::Sha256 => "SHA-256",
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.header_name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    /// Lowercase hex digest, as written in the control file.
+    pub hex: String,
+}
+
+impl FileChecksum {
+    pub fn new(algorithm: ChecksumAlgorithm, hex: &str) -> Self {
+        FileChecksum {
+            algorithm,
+            hex: hex.to_ascii_lowercase(),
+        }
+    }
+
+    /// Hashes `reader` to the end and compares the result against this digest.
+    pub fn verify(&self, reader: impl Read) -> Result<(), ZsyncError> {
+        verify_all(std::slice::from_ref(self), reader)
+    }
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            ChecksumAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    fn finalize_hex(self) -> String {
+        let digest = match self {
+            Hasher::Md5(h) => h.finalize().to_vec(),
+            Hasher::Sha1(h) => h.finalize().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+        };
+        to_hex(&digest)
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Checks every digest in `checksums` against `reader` in a single pass.
+pub(crate) fn verify_all(
+    checksums: &[FileChecksum],
+    mut reader: impl Read,
+) -> Result<(), ZsyncError> {
+    if checksums.is_empty() {
+        return Err(ZsyncError::MissingChecksum);
+    }
+
+    let mut hashers: Vec<Hasher> = checksums.iter().map(|c| Hasher::new(c.algorithm)).collect();
+
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for hasher in hashers.iter_mut() {
+            hasher.update(&buf[..n]);
+        }
+    }
+
+    for (checksum, hasher) in checksums.iter().zip(hashers) {
+        let actual = hasher.finalize_hex();
+        if actual != checksum.hex {
+            return Err(ZsyncError::ChecksumMismatch {
+                algorithm: checksum.algorithm,
+                expected: checksum.hex.clone(),
+                actual,
+            });
+        }
+    }
+
+    Ok(())
+}
@@ -1,16 +1,29 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec;
 
+use crate::cancel::CancellationToken;
+use crate::error::ZsyncError;
 use crate::meta_file::MetaFile;
 use crate::util::chaininghash::ChainingHash;
 use crate::util::checksumpair::ChecksumPair;
 use crate::util::configuration::Configuration;
-use crate::util::copy::{arr_copy, arr_fill};
 use crate::util::generator::Generator;
+use crate::util::read::read_block;
+use crate::util::rsum::{truncate_weak_sum, Rsum};
+
+const MEBIBYTE: usize = 1048576;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+// Hits a segment thread can queue before waiting for the replay to catch up.
+const HIT_BUFFER: usize = 65536;
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FilePart {
     pub start_offset: usize,
     pub end_offset: usize,
@@ -18,11 +31,60 @@ pub struct FilePart {
     pub offset: usize,
 }
 
+/// Where a target block was found locally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockSource {
+    /// Index of the seed, counting [`FileMaker::map_matcher`] calls from 0.
+    pub seed: usize,
+    /// Offset of the block in that seed.
+    pub offset: u64,
+}
+
+/// Outcome of [`FileMaker::map_matcher`]. Block counts cover every seed
+/// scanned so far.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MatchReport {
+    /// Index of the seed this scan read.
+    pub seed: usize,
+    /// Percentage of target blocks available locally.
+    pub progress: f64,
+    pub matched_blocks: usize,
+    /// Blocks this seed filled that no earlier seed had.
+    pub new_blocks: usize,
+    pub total_blocks: usize,
+    /// Number of seed bytes scanned.
+    pub seed_length: u64,
+    /// Source of each target block, `None` for blocks still missing.
+    pub sources: Vec<Option<BlockSource>>,
+}
+
+/// Progress of a running seed scan, passed to the callback set with
+/// [`FileMaker::set_progress_callback`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScanProgress {
+    pub bytes_scanned: u64,
+    pub total_bytes: u64,
+    /// Target blocks found so far, including those from earlier seeds.
+    pub matched_blocks: usize,
+    pub elapsed: Duration,
+}
+
+type ProgressCallback = Box<dyn FnMut(&ScanProgress) + Send>;
+
 pub struct FileMaker {
     metafile: MetaFile,
     hashtable: ChainingHash,
     file_map: Vec<i64>,
-    file_offset: i64,
+    block_seeds: Vec<Option<usize>>,
+    seeds: usize,
+    // Target block checksums by index.
+    blocks: Vec<ChecksumPair>,
+    progress: Option<ProgressCallback>,
+    scan_started: Instant,
+    cancel: Option<CancellationToken>,
 }
 
 impl FileMaker {
@@ -33,7 +95,16 @@ impl FileMaker {
             metafile: metafile.clone(),
             hashtable: metafile.hashtable.clone(),
             file_map: vec![-1; metafile.block_num as usize],
-            file_offset: 0,
+            block_seeds: vec![None; metafile.block_num as usize],
+            seeds: 0,
+            blocks: {
+                let mut blocks: Vec<ChecksumPair> = metafile.hashtable.iter().cloned().collect();
+                blocks.sort_by_key(|pair| pair.seq);
+                blocks
+            },
+            progress: None,
+            scan_started: Instant::now(),
+            cancel: None,
         }
     }
 
@@ -60,7 +131,7 @@ impl FileMaker {
                     start_offset: start_offset as usize,
                     end_offset: end_offset as usize,
                     block_length: block_length as usize,
-                    offset: offset as usize,
+                    offset,
                 });
 
                 // dbg!("Range: {} - {}", start_offset, end_offset);
@@ -113,285 +184,728 @@ impl FileMaker {
         (self.metafile.length as f64 * (100.0 - progress)) / 100.0
     }
 
-    pub fn map_matcher(&mut self, target_file: &Path) -> f64 {
-        let mut buffer_offset: i32 = 0;
+    /// Scans all of `seed`, from its start, for blocks of the target file.
+    ///
+    /// Can be called with several seeds in turn; each one only fills blocks
+    /// that earlier seeds did not provide. With `seq_matches` of 2 a block
+    /// only counts as found if the following target block matches right after
+    /// it, or if it continues a run of matches.
+    pub fn map_matcher<R: Read + Seek>(&mut self, mut seed: R) -> Result<MatchReport, ZsyncError> {
+        let (seed_index, matched_before) = self.begin_seed();
 
-        let file_length = target_file.metadata().unwrap().len();
+        let seed_length = seed.seek(SeekFrom::End(0))?;
+        seed.seek(SeekFrom::Start(0))?;
 
-        let mebi_byte = 1048576;
+        if !self.file_map.is_empty() {
+            let context = self.metafile.blocksize * self.seq_matches();
+            let mut scan = Scan::new(
+                self.metafile.blocksize,
+                self.metafile.checksum_bytes,
+                seed_index,
+            );
+            for_each_window(seed, 0, seed_length, context, |window, start, limit| {
+                self.check_cancelled()?;
+                self.scan(&mut scan, window, start, limit);
+                self.notify(limit, seed_length);
+                Ok(())
+            })?;
+        }
 
-        let mut config = Configuration::new();
-        config.block_length = self.metafile.blocksize as i32;
-        config.strong_sum_length = self.metafile.checksum_bytes as i32;
+        Ok(self.report(seed_index, matched_before, seed_length))
+    }
 
-        let mut gen = Generator::new(config);
+    /// Like [`FileMaker::map_matcher`], but scans `threads` segments of
+    /// `seed` in parallel.
+    ///
+    /// The threads run the strong checks themselves and stream the hits, in
+    /// order, to the calling thread. As they cannot know which blocks the
+    /// segments before theirs find, that one replays the serial scan over the
+    /// hits, and reads the seed itself only where the serial scan goes its
+    /// own way, so the result is identical to the serial scan.
+    pub fn map_matcher_parallel(
+        &mut self,
+        seed: &Path,
+        threads: usize,
+    ) -> Result<MatchReport, ZsyncError> {
+        let (seed_index, matched_before) = self.begin_seed();
+
+        let mut file = File::open(seed)?;
+        let seed_length = file.metadata()?.len();
+
+        if !self.file_map.is_empty() && seed_length > 0 {
+            let segment = seed_length.div_ceil(threads.max(1) as u64);
+            // Rounding the segments up can leave later threads nothing to scan.
+            let threads = seed_length.div_ceil(segment);
+            let target = Target::new(self);
+            self.check_cancelled()?;
+            let scanned = AtomicU64::new(0);
+
+            thread::scope(|scope| -> Result<(), ZsyncError> {
+                let segments: Vec<_> = (0..threads)
+                    .map(|i| {
+                        let (target, scanned) = (&target, &scanned);
+                        let end = ((i + 1) * segment).min(seed_length);
+                        let (hits, receiver) = mpsc::sync_channel(HIT_BUFFER);
+                        let handle =
+                            scope.spawn(move || target.scan(seed, i * segment, end, scanned, hits));
+                        (end, receiver, handle)
+                    })
+                    .collect();
+
+                let mut scan = Scan::new(
+                    self.metafile.blocksize,
+                    self.metafile.checksum_bytes,
+                    seed_index,
+                );
+                scan.filled = Some(Vec::new());
+                let mut notified = Instant::now();
+                for (end, receiver, handle) in segments {
+                    loop {
+                        self.check_cancelled()?;
+                        if self.progress.is_some() && notified.elapsed() >= PROGRESS_INTERVAL {
+                            self.notify(scanned.load(Ordering::Relaxed), seed_length);
+                            notified = Instant::now();
+                        }
+                        match receiver.recv_timeout(PROGRESS_INTERVAL) {
+                            Ok(hit) => {
+                                self.replay(&mut scan, &mut file, end, Some(hit))?;
+                                for seq in scan.filled.iter_mut().flatten() {
+                                    target.found[*seq].store(true, Ordering::Relaxed);
+                                }
+                                scan.filled = Some(Vec::new());
+                            }
+                            Err(RecvTimeoutError::Timeout) => {}
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                    handle
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e))?;
+                }
+                self.replay(&mut scan, &mut file, seed_length, None)
+            })?;
+            self.notify(seed_length, seed_length);
+        }
 
-        let mut back_buffer = vec![0u8; self.metafile.blocksize as usize];
-        let mut block_buffer = vec![0u8; self.metafile.blocksize as usize];
+        Ok(self.report(seed_index, matched_before, seed_length))
+    }
 
-        let mut file_buffer: Vec<u8>;
-        if self.metafile.length < mebi_byte && self.metafile.blocksize < self.metafile.length {
-            file_buffer = vec![0u8; self.metafile.length];
-        } else if self.metafile.blocksize > self.metafile.length
-            || self.metafile.blocksize > mebi_byte
-        {
-            file_buffer = vec![0u8; self.metafile.blocksize];
-        } else {
-            file_buffer = vec![0_u8; mebi_byte];
+    // Continues the serial scan from its position up to `hit`, or up to `end`
+    // without one, then runs its checks at the hit. `end` is where the
+    // segment of the hit ends.
+    fn replay(
+        &mut self,
+        scan: &mut Scan,
+        seed: &mut File,
+        end: u64,
+        hit: Option<Hit>,
+    ) -> Result<(), ZsyncError> {
+        let blocksize = self.metafile.blocksize as u64;
+        let until = hit.as_ref().map_or(end, |hit| hit.position);
+
+        // The thread found nothing before the hit, but only checked the
+        // blocks continuing its own runs of matches.
+        while scan.position < until {
+            let next = match scan.next_match.take() {
+                Some(next) => next,
+                None => break,
+            };
+            if self.check_block(scan, seed, next)? {
+                self.record(next, scan);
+                scan.position += blocksize;
+            } else {
+                scan.position += 1;
+            }
         }
 
-        let mut first_block = true;
-        let len = file_buffer.len();
-        let blocksize = self.metafile.blocksize;
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return Ok(()),
+        };
+        if hit.position >= scan.position {
+            scan.position = hit.position;
+            self.check_hit(scan, seed, &hit)?;
+        }
 
-        let mut new_byte: u8;
+        // The thread skipped the rest of the block it matched, which the
+        // serial scan may not have.
+        let skipped = (hit.position + blocksize).min(end);
+        if hit.skips() && scan.position < skipped {
+            self.scan_range(scan, seed, skipped)?;
+        }
+        Ok(())
+    }
 
-        let mut last_match: i64 = 0;
+    // `check_position` at the position of `hit`, from the checks it holds.
+    fn check_hit(&mut self, scan: &mut Scan, seed: &mut File, hit: &Hit) -> Result<(), ZsyncError> {
+        let blocksize = self.metafile.blocksize as u64;
+
+        if let Some(next) = scan.next_match.take() {
+            let matched = match hit.single {
+                Some((seq, matched)) if seq == next => matched,
+                _ => self.check_block(scan, seed, next)?,
+            };
+            if matched {
+                self.record(next, scan);
+                scan.position += blocksize;
+                return Ok(());
+            }
+        }
 
-        let mut n: i32;
-        let mut weak_sum = 0;
-        let mut strong_sum: Vec<u8>;
-        let mut end = false;
+        let found = self
+            .hashtable
+            .find_all(hit.weak)
+            .map(|pair| pair.seq as usize)
+            .find(|seq| hit.pairs.contains(seq));
+        match found {
+            Some(seq) => {
+                self.record(seq, scan);
+                scan.position += blocksize;
+            }
+            None => scan.position += 1,
+        }
+        Ok(())
+    }
 
-        let mut in_buf = File::open(target_file).expect("Unable to open file");
+    // Whether target block `seq` is at the scan position, reading it from `seed`.
+    fn check_block(
+        &self,
+        scan: &mut Scan,
+        seed: &mut File,
+        seq: usize,
+    ) -> Result<bool, ZsyncError> {
+        let blocksize = self.metafile.blocksize;
+        let mut block = vec![0u8; blocksize];
+        seed.seek(SeekFrom::Start(scan.position))?;
+        read_block(seed, &mut block)?;
+
+        scan.weak.first(&block, 0, blocksize);
+        let target = &self.blocks[seq];
+        Ok(target.weak == self.update_weak_sum(scan.weak.get_value())
+            && target.strong == scan.gen.generate_strong_sum(&block, 0, blocksize))
+    }
 
-        while self.file_offset as u64 != file_length {
-            file_buffer.resize(len, 0);
-            n = in_buf.read(&mut file_buffer).expect("cant read") as i32; // maybe reads too much
-                                                                          // n = file_buffer.len() as i32;
+    // Runs the serial scan up to `end`, reading the seed from the scan position.
+    fn scan_range(&mut self, scan: &mut Scan, seed: &mut File, end: u64) -> Result<(), ZsyncError> {
+        let start = scan.position;
+        let context = self.metafile.blocksize * self.seq_matches();
+        let mut window = vec![0u8; (end - start) as usize + context];
+        seed.seek(SeekFrom::Start(start))?;
+        read_block(seed, &mut window)?;
+
+        scan.rolled = false;
+        self.scan(scan, &window, start, end);
+        Ok(())
+    }
 
-            if first_block {
-                weak_sum = gen.generate_weak_sum(&mut file_buffer, 0);
-                buffer_offset = self.metafile.blocksize as i32;
-                let weak = self.update_weak_sum(weak_sum);
+    /// Like [`FileMaker::map_matcher`], but scans a seed that is already in
+    /// memory, such as a memory-mapped file, as one slice instead of copying
+    /// it through a read buffer.
+    pub fn map_matcher_slice(&mut self, seed: &[u8]) -> Result<MatchReport, ZsyncError> {
+        let (seed_index, matched_before) = self.begin_seed();
 
-                if self.hash_look_up(weak, Vec::new()) {
-                    strong_sum = gen.generate_strong_sum(&mut file_buffer, 0, blocksize);
+        if !self.file_map.is_empty() {
+            let context = self.metafile.blocksize * self.seq_matches();
+            let mut scan = Scan::new(
+                self.metafile.blocksize,
+                self.metafile.checksum_bytes,
+                seed_index,
+            );
 
-                    let match_ = self.hash_look_up(weak_sum, strong_sum);
-                    if match_ {
-                        last_match = self.file_offset;
-                    }
-                }
-                self.file_offset += 1;
-                first_block = false;
+            let limit = seed.len().saturating_sub(context) as u64;
+            let mut scanned = 0;
+            while scanned < limit {
+                let next = (scanned + MEBIBYTE as u64).min(limit);
+                self.check_cancelled()?;
+                self.scan(&mut scan, seed, 0, next);
+                self.notify(next, seed.len() as u64);
+                scanned = next;
             }
 
-            while (buffer_offset as usize) < file_buffer.len() {
-                new_byte = file_buffer[buffer_offset as usize];
+            // Positions near the end need the zero padding after the data.
+            let mut tail = seed[limit as usize..].to_vec();
+            tail.resize(tail.len() + context, 0);
+            self.scan(&mut scan, &tail, limit, seed.len() as u64);
+            self.notify(seed.len() as u64, seed.len() as u64);
+        }
 
-                if (self.file_offset + self.metafile.blocksize as i64) as u64 > file_length {
-                    new_byte = 0;
-                }
+        Ok(self.report(seed_index, matched_before, seed.len() as u64))
+    }
 
-                weak_sum = gen.generate_roll_sum(new_byte);
+    /// Memory-maps `seed` and scans it with [`FileMaker::map_matcher_slice`].
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated until this returns, see
+    /// [`memmap2::Mmap::map`].
+    #[cfg(feature = "mmap")]
+    pub unsafe fn map_matcher_mmap(&mut self, seed: &File) -> Result<MatchReport, ZsyncError> {
+        let map = memmap2::Mmap::map(seed)?;
+        self.map_matcher_slice(&map)
+    }
 
-                let mut found = false;
+    /// Calls `callback` regularly while a seed is scanned: after every read
+    /// window, or every 100 ms while [`FileMaker::map_matcher_parallel`] runs.
+    pub fn set_progress_callback(&mut self, callback: impl FnMut(&ScanProgress) + Send + 'static) {
+        self.progress = Some(Box::new(callback));
+    }
 
-                if self.file_offset >= last_match + blocksize as i64 {
-                    let w_sum = self.update_weak_sum(weak_sum);
-                    if self.hash_look_up(w_sum, Vec::new()) {
-                        found = true;
-                    }
-                } else {
-                }
+    pub fn clear_progress_callback(&mut self) {
+        self.progress = None;
+    }
 
-                if found {
-                    if (self.file_offset + self.metafile.blocksize as i64) as u64 > file_length {
-                        if n > 0 {
-                            for i in (n as usize)..file_buffer.len() {
-                                file_buffer[i] = 0;
-                            }
-                        } else {
-                            let offset = file_buffer.len() - self.metafile.blocksize
-                                + buffer_offset as usize
-                                + 1;
-                            arr_copy(
-                                &file_buffer,
-                                offset as usize,
-                                &mut block_buffer,
-                                0,
-                                file_buffer.len() - offset as usize,
-                            );
-
-                            let block_buffer_len = block_buffer.len();
-                            arr_fill(
-                                &mut block_buffer,
-                                file_buffer.len() - offset as usize,
-                                block_buffer_len,
-                                0,
-                            )
-                        }
-                    }
-                    if (buffer_offset - self.metafile.blocksize as i32 + 1) < 0 {
-                        if n > 0 {
-                            arr_copy(
-                                &back_buffer,
-                                ((back_buffer.len() + buffer_offset as usize) as i32
-                                    - self.metafile.blocksize as i32
-                                    + 1) as usize,
-                                &mut block_buffer,
-                                0,
-                                (self.metafile.blocksize as i32 - buffer_offset - 1) as usize,
-                            );
-
-                            arr_copy(
-                                &file_buffer,
-                                0,
-                                &mut block_buffer,
-                                (self.metafile.blocksize as i32 - buffer_offset - 1) as usize,
-                                buffer_offset as usize + 1,
-                            );
-                        }
-                        strong_sum =
-                            gen.generate_strong_sum(&mut block_buffer, 0, blocksize as usize);
+    /// Makes scans return [`ZsyncError::Cancelled`] once `token` is cancelled.
+    ///
+    /// Blocks found before that stay recorded, so the scan can be repeated
+    /// or another seed scanned with the same `FileMaker`.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel = Some(token);
+    }
 
-                        let temp_weak_sum = self.update_weak_sum(weak_sum);
-                        let match_ = self.hash_look_up(temp_weak_sum, strong_sum);
-                        if match_ {
-                            last_match = self.file_offset;
-                        }
-                    } else {
-                        strong_sum = gen.generate_strong_sum(
-                            &mut file_buffer,
-                            (buffer_offset - blocksize as i32 + 1) as usize,
-                            blocksize,
-                        );
-                        let temp_weak_sum = self.update_weak_sum(weak_sum);
-                        let match_ = self.hash_look_up(temp_weak_sum, strong_sum);
-                        if match_ {
-                            last_match = self.file_offset;
-                        }
-                    }
-                }
+    fn check_cancelled(&self) -> Result<(), ZsyncError> {
+        match &self.cancel {
+            Some(token) => token.check(),
+            None => Ok(()),
+        }
+    }
 
-                self.file_offset += 1;
-                //println!("Offset: {}", self.file_offset);
-                if self.file_offset as u64 == file_length {
-                    end = true;
-                    break;
-                }
+    fn notify(&mut self, bytes_scanned: u64, total_bytes: u64) {
+        let matched_blocks = self.matched_blocks();
+        let elapsed = self.scan_started.elapsed();
+        if let Some(callback) = self.progress.as_mut() {
+            callback(&ScanProgress {
+                bytes_scanned: bytes_scanned.min(total_bytes),
+                total_bytes,
+                matched_blocks,
+                elapsed,
+            });
+        }
+    }
+
+    fn begin_seed(&mut self) -> (usize, usize) {
+        self.scan_started = Instant::now();
+        self.seeds += 1;
+        (self.seeds - 1, self.matched_blocks())
+    }
+
+    fn report(&self, seed: usize, matched_before: usize, seed_length: u64) -> MatchReport {
+        let matched_blocks = self.matched_blocks();
+        MatchReport {
+            seed,
+            progress: self.match_control(),
+            matched_blocks,
+            new_blocks: matched_blocks.saturating_sub(matched_before),
+            total_blocks: self.file_map.len(),
+            seed_length,
+            sources: self.block_sources(),
+        }
+    }
 
-                buffer_offset += 1;
+    fn seq_matches(&self) -> usize {
+        if self.metafile.seq_num == 2 && self.blocks.len() > 1 {
+            2
+        } else {
+            1
+        }
+    }
+
+    // Scans the positions before `limit`; `window` holds the seed from
+    // `window_start` on.
+    fn scan(&mut self, scan: &mut Scan, window: &[u8], window_start: u64, limit: u64) {
+        let blocksize = self.metafile.blocksize;
+        let seq_matches = self.seq_matches();
+
+        while scan.position < limit {
+            let i = (scan.position - window_start) as usize;
+            if !scan.rolled {
+                scan.weak.first(window, i, blocksize);
+                if seq_matches == 2 {
+                    scan.next_weak.first(window, i + blocksize, blocksize);
+                }
+                scan.rolled = true;
             }
 
-            arr_copy(
-                &file_buffer,
-                file_buffer.len() - self.metafile.blocksize,
-                &mut back_buffer,
-                0,
-                self.metafile.blocksize,
-            );
-            buffer_offset = 0;
+            if self.check_position(scan, window, i) {
+                scan.position += blocksize as u64;
+                scan.rolled = false;
+                continue;
+            }
 
-            if end {
-                break;
+            scan.weak.roll(window[i + blocksize]);
+            if seq_matches == 2 {
+                scan.next_weak.roll(window[i + 2 * blocksize]);
             }
+            scan.position += 1;
         }
-        self.match_control()
     }
 
-    #[allow(clippy::collapsible_if)]
-    pub fn match_control(&mut self) -> f64 {
-        let mut missing = 0;
+    // Looks for a target block at the current scan position.
+    fn check_position(&mut self, scan: &mut Scan, window: &[u8], i: usize) -> bool {
+        let blocksize = self.metafile.blocksize;
+        let weak = self.update_weak_sum(scan.weak.get_value());
+
+        // The block after a match only has to match on its own.
+        if let Some(next) = scan.next_match.take() {
+            let block = &self.blocks[next];
+            if block.weak == weak
+                && block.strong == scan.gen.generate_strong_sum(window, i, blocksize)
+            {
+                self.record(next, scan);
+                return true;
+            }
+        }
 
-        for i in 0..self.file_map.len() {
-            if self.metafile.seq_num == 2 {
-                if i > 0 && i < self.file_map.len() - 1 {
-                    if self.file_map[i - 1] == -1
-                        && self.file_map[i] != -1
-                        && self.file_map[i + 1] == -1
-                    {
-                        self.file_map[i] = -1;
-                    }
-                } else if i == 0 {
-                    if self.file_map[i] != -1 && self.file_map[i + 1] == -1 {
-                        self.file_map[i] = -1;
-                    }
-                } else if i == self.file_map.len() - 1 {
-                    if self.file_map[i] != -1 && self.file_map[i - 1] == -1 {
-                        self.file_map[i] = -1;
-                    }
-                }
+        let mut candidates: Vec<usize> = self
+            .hashtable
+            .find_all(weak)
+            .map(|pair| pair.seq as usize)
+            .collect();
+        if self.seq_matches() == 2 {
+            let next_weak = self.update_weak_sum(scan.next_weak.get_value());
+            candidates.retain(|&seq| {
+                self.blocks
+                    .get(seq + 1)
+                    .is_some_and(|b| b.weak == next_weak)
+            });
+        }
+        if candidates.is_empty() {
+            return false;
+        }
+
+        let strong = scan.gen.generate_strong_sum(window, i, blocksize);
+        let mut next_strong = None;
+        for seq in candidates {
+            if self.blocks[seq].strong != strong {
+                continue;
             }
-            if self.file_map[i] == -1 {
-                missing += 1;
+            if self.seq_matches() == 2 {
+                let next_strong = next_strong.get_or_insert_with(|| {
+                    scan.gen
+                        .generate_strong_sum(window, i + blocksize, blocksize)
+                });
+                if self.blocks[seq + 1].strong != *next_strong {
+                    continue;
+                }
             }
+            self.record(seq, scan);
+            return true;
         }
 
-        if !self.file_map.is_empty() {
-            (((self.file_map.len() - missing) as f64 / self.file_map.len() as f64) * 100.0) as f64
-        } else {
-            0.0
+        false
+    }
+
+    // Marks target block `seq` as found at the scan position, along with
+    // every other missing block with the same checksums.
+    fn record(&mut self, seq: usize, scan: &mut Scan) {
+        if self.seq_matches() == 2 && seq + 1 < self.blocks.len() {
+            scan.next_match = Some(seq + 1);
         }
+        if self.file_map[seq] != -1 {
+            return;
+        }
+
+        let block = &self.blocks[seq];
+        let identical: Vec<usize> = self
+            .hashtable
+            .find_all(block.weak)
+            .filter(|pair| pair.strong == block.strong)
+            .map(|pair| pair.seq as usize)
+            .collect();
+        if let Some(filled) = scan.filled.as_mut() {
+            filled.extend_from_slice(&identical);
+        }
+        for seq in identical {
+            self.file_map[seq] = scan.position as i64;
+            self.block_seeds[seq] = Some(scan.seed);
+            self.hashtable.delete(&self.blocks[seq]);
+        }
+    }
+
+    fn matched_blocks(&self) -> usize {
+        self.file_map.iter().filter(|&&offset| offset != -1).count()
     }
 
-    pub fn update_weak_sum(&mut self, weak: i32) -> i32 {
-        let rsum: Vec<u8>;
+    /// Seed and offset each target block was found at, `None` for missing blocks.
+    pub fn block_sources(&self) -> Vec<Option<BlockSource>> {
+        self.file_map
+            .iter()
+            .zip(&self.block_seeds)
+            .map(|(&offset, &seed)| match seed {
+                Some(seed) if offset != -1 => Some(BlockSource {
+                    seed,
+                    offset: offset as u64,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
 
-        match self.metafile.rsum_bytes {
-            2 => rsum = vec![0, 0, (weak >> 24) as u8, ((weak << 8) >> 24) as u8],
-            3 => {
-                rsum = vec![
-                    ((weak << 8) >> 24) as u8,
-                    0,
-                    ((weak << 24) >> 24) as u8,
-                    (weak >> 24) as u8,
-                ]
-            }
-            4 => {
-                rsum = vec![
-                    (weak >> 24) as u8,
-                    ((weak << 8) >> 24) as u8,
-                    ((weak << 16) >> 24) as u8,
-                    ((weak << 24) >> 24) as u8,
-                ]
+    /// Percentage of target blocks found so far.
+    pub fn match_control(&self) -> f64 {
+        if self.file_map.is_empty() {
+            return 0.0;
+        }
+        (self.matched_blocks() as f64 / self.file_map.len() as f64) * 100.0
+    }
+
+    pub fn update_weak_sum(&self, weak: i32) -> i32 {
+        truncate_weak_sum(weak, self.metafile.rsum_bytes)
+    }
+}
+
+// Feeds `seed`, positioned at `start`, to `scan` one read window at a time,
+// as the window, the seed offset it starts at and the position to scan up to.
+// Every position before `end` comes with `context` bytes after it, zero
+// padded past the end of the seed.
+fn for_each_window(
+    mut seed: impl Read,
+    start: u64,
+    end: u64,
+    context: usize,
+    mut scan: impl FnMut(&[u8], u64, u64) -> Result<(), ZsyncError>,
+) -> Result<(), ZsyncError> {
+    let mut window = vec![0u8; context + MEBIBYTE.max(context)];
+    let mut window_start = start;
+    let mut filled = read_block(&mut seed, &mut window)?;
+
+    loop {
+        if filled < window.len() {
+            if window_start + (filled as u64) < end {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
             }
-            _ => rsum = vec![0; 4],
+            // Zero padding, as the last target block is hashed with.
+            window.truncate(filled);
+            window.resize(filled + context, 0);
+            return scan(&window, window_start, end);
         }
 
-        let mut weak_sum: i32 = 0;
-        weak_sum += (rsum[0] as i32 & 0x000000FF) << 24;
-        weak_sum += (rsum[1] as i32 & 0x000000FF) << 16;
-        weak_sum += (rsum[2] as i32 & 0x000000FF) << 8;
-        weak_sum += rsum[3] as i32 & 0x000000FF;
+        let limit = window_start + (window.len() - context) as u64;
+        if limit >= end {
+            return scan(&window, window_start, end);
+        }
+        scan(&window, window_start, limit)?;
 
-        weak_sum
+        // Keep the context of the next positions at the front.
+        let keep = window.len() - context;
+        window.copy_within(keep.., 0);
+        window_start = limit;
+        filled = context + read_block(&mut seed, &mut window[context..])?;
     }
+}
 
-    fn hash_look_up(&mut self, weak_sum: i32, strong_sum: Vec<u8>) -> bool {
-        if strong_sum.is_empty() {
-            let mut p = ChecksumPair::new();
-            p.weak = weak_sum;
-            p.offset = -1;
+// Target blocks a segment thread of `map_matcher_parallel` found at one seed
+// position.
+struct Hit {
+    position: u64,
+    // Truncated weak checksum at `position`.
+    weak: i32,
+    // Blocks matching here, with seq_matches 2 along with the block after.
+    pairs: Vec<usize>,
+    // The block continuing the thread's run of matches, and whether it matches.
+    single: Option<(usize, bool)>,
+}
 
-            let link = self.hashtable.find(&p);
-            if link.is_some() {
-                return true;
-            }
-        } else {
-            let mut p = ChecksumPair::new();
-            p.weak = weak_sum;
-            p.strong = strong_sum.clone();
-            p.offset = -1;
-
-            let link = self.hashtable.find_match(&p);
-            if let Some(link) = link {
-                let seq = link.seq;
-                self.file_map[seq as usize] = self.file_offset;
-
-                let mut del_p = ChecksumPair::new();
-                del_p.weak = weak_sum;
-                del_p.strong = strong_sum;
-                del_p.offset = (self.metafile.blocksize * seq as usize) as i64;
-                del_p.length = self.metafile.blocksize as i32;
-                del_p.seq = seq;
-
-                self.hashtable.delete(&del_p);
-                return true;
+impl Hit {
+    // Whether the thread took this as a match and skipped the rest of the block.
+    fn skips(&self) -> bool {
+        !self.pairs.is_empty() || self.single.is_some_and(|(_, matched)| matched)
+    }
+}
+
+// Copy of the target checksums the segment threads check seed positions
+// against, as they were when the scan started.
+struct Target {
+    blocksize: usize,
+    rsum_bytes: u32,
+    checksum_bytes: u32,
+    pairs: bool,
+    hashtable: ChainingHash,
+    blocks: Vec<ChecksumPair>,
+    // Blocks the replay has found since, which can no longer match.
+    found: Vec<AtomicBool>,
+    cancel: Option<CancellationToken>,
+}
+
+impl Target {
+    fn new(fm: &FileMaker) -> Self {
+        Target {
+            blocksize: fm.metafile.blocksize,
+            rsum_bytes: fm.metafile.rsum_bytes,
+            checksum_bytes: fm.metafile.checksum_bytes,
+            pairs: fm.seq_matches() == 2,
+            hashtable: fm.hashtable.clone(),
+            blocks: fm.blocks.clone(),
+            found: fm.blocks.iter().map(|_| AtomicBool::new(false)).collect(),
+            cancel: fm.cancel.clone(),
+        }
+    }
+
+    // Scans `start..end` of `seed` like the serial scan, except that found
+    // blocks stay in the hash table, and sends the hits in order. `scanned`
+    // is advanced as the scan moves on.
+    fn scan(
+        &self,
+        seed: &Path,
+        start: u64,
+        end: u64,
+        scanned: &AtomicU64,
+        hits: SyncSender<Hit>,
+    ) -> Result<(), ZsyncError> {
+        let blocksize = self.blocksize;
+        let context = if self.pairs { 2 * blocksize } else { blocksize };
+
+        let mut file = File::open(seed)?;
+        file.seek(SeekFrom::Start(start))?;
+        let reader = BufReader::new(file).take(end - start + context as u64);
+
+        let mut scan = Scan::new(blocksize, self.checksum_bytes, 0);
+        scan.position = start;
+        let mut passed = start;
+
+        for_each_window(
+            reader,
+            start,
+            end,
+            context,
+            |window, window_start, limit| {
+                if let Some(token) = &self.cancel {
+                    token.check()?;
+                }
+                scanned.fetch_add(limit - passed, Ordering::Relaxed);
+                passed = limit;
+
+                while scan.position < limit {
+                    let i = (scan.position - window_start) as usize;
+                    if !scan.rolled {
+                        scan.weak.first(window, i, blocksize);
+                        if self.pairs {
+                            scan.next_weak.first(window, i + blocksize, blocksize);
+                        }
+                        scan.rolled = true;
+                    }
+
+                    if let Some(hit) = self.check_position(&mut scan, window, i) {
+                        let skips = hit.skips();
+                        // Fails once the calling thread stopped replaying.
+                        hits.send(hit).map_err(|_| ZsyncError::Cancelled)?;
+                        if skips {
+                            scan.position += blocksize as u64;
+                            scan.rolled = false;
+                            continue;
+                        }
+                    }
+
+                    scan.weak.roll(window[i + blocksize]);
+                    if self.pairs {
+                        scan.next_weak.roll(window[i + 2 * blocksize]);
+                    }
+                    scan.position += 1;
+                }
+                Ok(())
+            },
+        )
+    }
+
+    // Like `FileMaker::check_position`, but collects every target block that
+    // matches instead of recording the first.
+    fn check_position(&self, scan: &mut Scan, window: &[u8], i: usize) -> Option<Hit> {
+        let blocksize = self.blocksize;
+        let weak = truncate_weak_sum(scan.weak.get_value(), self.rsum_bytes);
+        let mut strong = None;
+
+        let single = scan.next_match.take().map(|next| {
+            let block = &self.blocks[next];
+            let strong =
+                strong.get_or_insert_with(|| scan.gen.generate_strong_sum(window, i, blocksize));
+            (next, block.weak == weak && block.strong == *strong)
+        });
+
+        let mut candidates: Vec<usize> = self
+            .hashtable
+            .find_all(weak)
+            .map(|pair| pair.seq as usize)
+            .filter(|&seq| !self.found[seq].load(Ordering::Relaxed))
+            .collect();
+        if self.pairs {
+            let next_weak = truncate_weak_sum(scan.next_weak.get_value(), self.rsum_bytes);
+            candidates.retain(|&seq| {
+                self.blocks
+                    .get(seq + 1)
+                    .is_some_and(|b| b.weak == next_weak)
+            });
+        }
+
+        let mut pairs = Vec::new();
+        if !candidates.is_empty() {
+            let strong =
+                strong.get_or_insert_with(|| scan.gen.generate_strong_sum(window, i, blocksize));
+            let mut next_strong = None;
+            for seq in candidates {
+                if self.blocks[seq].strong != *strong {
+                    continue;
+                }
+                if self.pairs {
+                    let next_strong = next_strong.get_or_insert_with(|| {
+                        scan.gen
+                            .generate_strong_sum(window, i + blocksize, blocksize)
+                    });
+                    if self.blocks[seq + 1].strong != *next_strong {
+                        continue;
+                    }
+                }
+                pairs.push(seq);
             }
         }
 
-        false
+        if single.is_none() && pairs.is_empty() {
+            return None;
+        }
+        let matched = match single {
+            Some((seq, true)) => Some(seq),
+            _ => pairs.first().copied(),
+        };
+        if let Some(seq) = matched.filter(|&seq| self.pairs && seq + 1 < self.blocks.len()) {
+            scan.next_match = Some(seq + 1);
+        }
+
+        Some(Hit {
+            position: scan.position,
+            weak,
+            pairs,
+            single,
+        })
+    }
+}
+
+// Rolling state of a seed scan, carried across refills of the read window.
+struct Scan {
+    seed: usize,
+    position: u64,
+    // Whether the weak sums are those of the blocks at `position`.
+    rolled: bool,
+    weak: Rsum,
+    next_weak: Rsum,
+    next_match: Option<usize>,
+    gen: Generator,
+    // Blocks recorded, when collected for the segment threads.
+    filled: Option<Vec<usize>>,
+}
+
+impl Scan {
+    fn new(blocksize: usize, checksum_bytes: u32, seed: usize) -> Self {
+        let mut config = Configuration::new();
+        config.block_length = blocksize as i32;
+        config.strong_sum_length = checksum_bytes as i32;
+
+        Scan {
+            seed,
+            position: 0,
+            rolled: false,
+            weak: Rsum::new(),
+            next_weak: Rsum::new(),
+            next_match: None,
+            gen: Generator::new(config),
+            filled: None,
+        }
     }
 }
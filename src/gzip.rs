@@ -0,0 +1,649 @@
+use std::io::{self, ErrorKind, Read};
+
+use crate::error::ZsyncError;
+use crate::file_maker::FilePart;
+use crate::meta_file::MetaFile;
+
+const WINDOW_SIZE: usize = 32768;
+
+// Z-Map2 stores 16 bit deltas, with the top bit of the output delta marking
+// points that are not block starts. A new point is added before either delta
+// can overflow, leaving room for the longest symbol.
+const NOT_BLOCK_START: u16 = 0x8000;
+const MAX_IN_DELTA: u64 = 0xffff - 128;
+const MAX_OUT_DELTA: u64 = 0x7fff - 258;
+
+// Table for the reflected CRC-32 (polynomial 0xedb88320) of the gzip trailer.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+fn update_crc(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |c, &byte| {
+        CRC_TABLE[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+/// One point of the `Z-Map2` block map of a gzip-compressed target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ZMapEntry {
+    /// Bit offset into the compressed file.
+    pub compressed_bits: u64,
+    /// Byte offset into the uncompressed content.
+    pub uncompressed_offset: u64,
+    /// Whether a deflate block starts here. Decoding can only begin at block
+    /// starts; the other points just bound how much needs to be fetched.
+    pub block_start: bool,
+}
+
+/// A span of the compressed file to fetch for some uncompressed bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ZRange {
+    /// First byte of the compressed file to fetch.
+    pub compressed_start: u64,
+    /// End of the compressed bytes to fetch, exclusive. `None` means up to
+    /// the end of the file, for maps that don't cover the end of the stream.
+    pub compressed_end: Option<u64>,
+    /// Bit offset of the deflate block decoding starts at.
+    pub start_bits: u64,
+    /// Uncompressed offset of that block; up to 32 KiB of the content just
+    /// before it are needed to resolve back-references.
+    pub window_end: u64,
+    pub uncompressed_start: u64,
+    pub uncompressed_end: u64,
+}
+
+impl MetaFile {
+    /// Maps missing parts of the uncompressed content onto the ranges of
+    /// `z_filename` that have to be fetched. Parts whose compressed ranges
+    /// overlap are merged into one range that also covers the bytes between them.
+    pub fn compressed_ranges(&self, parts: &[FilePart]) -> Vec<ZRange> {
+        let mut ranges: Vec<ZRange> = Vec::new();
+
+        for part in parts {
+            let start = part.start_offset as u64;
+            let end = (part.end_offset as u64).min(self.length as u64);
+            if start >= end {
+                continue;
+            }
+
+            let block = self
+                .z_map
+                .iter()
+                .rev()
+                .find(|e| e.block_start && e.uncompressed_offset <= start);
+            let block = match block {
+                Some(block) => *block,
+                None => continue,
+            };
+            let compressed_end = self
+                .z_map
+                .iter()
+                .find(|e| e.uncompressed_offset >= end)
+                .map(|e| e.compressed_bits.div_ceil(8));
+
+            if let Some(last) = ranges.last_mut() {
+                let overlaps = match last.compressed_end {
+                    Some(last_end) => block.compressed_bits / 8 < last_end,
+                    None => true,
+                };
+                if overlaps {
+                    last.compressed_end = compressed_end;
+                    last.uncompressed_end = end;
+                    continue;
+                }
+            }
+
+            ranges.push(ZRange {
+                compressed_start: block.compressed_bits / 8,
+                compressed_end,
+                start_bits: block.compressed_bits,
+                window_end: block.uncompressed_offset,
+                uncompressed_start: start,
+                uncompressed_end: end,
+            });
+        }
+
+        ranges
+    }
+
+    /// Decodes the uncompressed bytes of `range` from `compressed`, which
+    /// holds the compressed file starting at `range.compressed_start`.
+    ///
+    /// `window` must end at `range.window_end` of the uncompressed content
+    /// and hold the 32 KiB before it, or everything from the start if less.
+    pub fn inflate_range(
+        &self,
+        range: &ZRange,
+        compressed: &[u8],
+        window: &[u8],
+    ) -> Result<Vec<u8>, ZsyncError> {
+        let skip = (range.start_bits - range.compressed_start * 8) as u32;
+        let mut bits = BitReader::new(compressed, range.start_bits - skip as u64);
+        bits.bits(skip)?;
+
+        let mut inflater = Inflater::new(bits, range.window_end, None);
+        inflater.preset_window(window);
+
+        let mut discard = vec![0u8; (range.uncompressed_start - range.window_end) as usize];
+        inflater.read_exact(&mut discard)?;
+        let mut out = vec![0u8; (range.uncompressed_end - range.uncompressed_start) as usize];
+        inflater.read_exact(&mut out)?;
+
+        Ok(out)
+    }
+
+    /// Splits the `Recompress` header into the original gzip header bytes and
+    /// the `gzip` options that reproduce the compressed file.
+    pub fn recompress_options(&self) -> Option<(Vec<u8>, Vec<String>)> {
+        let mut fields = self.recompress.split_whitespace();
+        let hex = fields.next()?;
+        if hex.len() % 2 != 0 {
+            return None;
+        }
+        let header = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+
+        Some((header, fields.map(String::from).collect()))
+    }
+}
+
+pub(crate) fn decode_z_map(raw: &[u8]) -> Vec<ZMapEntry> {
+    let mut compressed_bits = 0;
+    let mut uncompressed_offset = 0;
+
+    raw.chunks_exact(4)
+        .map(|entry| {
+            let in_delta = u16::from_be_bytes([entry[0], entry[1]]);
+            let out_delta = u16::from_be_bytes([entry[2], entry[3]]);
+            compressed_bits += in_delta as u64;
+            uncompressed_offset += (out_delta & !NOT_BLOCK_START) as u64;
+
+            ZMapEntry {
+                compressed_bits,
+                uncompressed_offset,
+                block_start: out_delta & NOT_BLOCK_START == 0,
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn encode_z_map(entries: &[ZMapEntry]) -> Result<Vec<u8>, ZsyncError> {
+    let mut raw = Vec::with_capacity(entries.len() * 4);
+    let (mut compressed_bits, mut uncompressed_offset) = (0, 0);
+
+    for entry in entries {
+        let in_delta = entry.compressed_bits.checked_sub(compressed_bits);
+        let out_delta = entry.uncompressed_offset.checked_sub(uncompressed_offset);
+        let (in_delta, out_delta) = match (in_delta, out_delta) {
+            (Some(i), Some(o)) if i <= 0xffff && o < NOT_BLOCK_START as u64 => (i as u16, o as u16),
+            _ => {
+                return Err(ZsyncError::InvalidGzip(String::from(
+                    "Z-Map2 points out of order or too far apart",
+                )))
+            }
+        };
+        let flag = if entry.block_start {
+            0
+        } else {
+            NOT_BLOCK_START
+        };
+
+        raw.extend_from_slice(&in_delta.to_be_bytes());
+        raw.extend_from_slice(&(out_delta | flag).to_be_bytes());
+        compressed_bits = entry.compressed_bits;
+        uncompressed_offset = entry.uncompressed_offset;
+    }
+
+    Ok(raw)
+}
+
+/// Reads the gzip member header off `reader`, returning its raw bytes.
+pub(crate) fn read_gzip_header(reader: &mut impl Read) -> Result<Vec<u8>, ZsyncError> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    let mut header = vec![0u8; 10];
+    reader.read_exact(&mut header)?;
+    if header[0..3] != [0x1f, 0x8b, 0x08] {
+        return Err(ZsyncError::InvalidGzip(String::from(
+            "not a deflate compressed gzip file",
+        )));
+    }
+
+    let flags = header[3];
+    let mut byte = [0u8; 1];
+    if flags & FEXTRA != 0 {
+        let mut xlen = [0u8; 2];
+        reader.read_exact(&mut xlen)?;
+        header.extend_from_slice(&xlen);
+        let mut extra = vec![0u8; u16::from_le_bytes(xlen) as usize];
+        reader.read_exact(&mut extra)?;
+        header.extend_from_slice(&extra);
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            loop {
+                reader.read_exact(&mut byte)?;
+                header.push(byte[0]);
+                if byte[0] == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        let mut crc = [0u8; 2];
+        reader.read_exact(&mut crc)?;
+        header.extend_from_slice(&crc);
+    }
+
+    Ok(header)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+// LSB-first bit reader over deflate data, tracking the absolute bit offset.
+pub(crate) struct BitReader<R> {
+    inner: R,
+    buf: u64,
+    count: u32,
+    position: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    pub(crate) fn new(inner: R, position: u64) -> Self {
+        BitReader {
+            inner,
+            buf: 0,
+            count: 0,
+            position,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let mut byte = [0u8; 1];
+            self.inner.read_exact(&mut byte)?;
+            self.buf |= (byte[0] as u64) << self.count;
+            self.count += 8;
+        }
+        let value = (self.buf & ((1u64 << n) - 1)) as u32;
+        self.buf >>= n;
+        self.count -= n;
+        self.position += n as u64;
+        Ok(value)
+    }
+
+    fn align(&mut self) -> io::Result<()> {
+        let skip = ((8 - self.position % 8) % 8) as u32;
+        self.bits(skip).map(|_| ())
+    }
+
+    fn at_end(&mut self) -> io::Result<bool> {
+        if self.count > 0 {
+            return Ok(false);
+        }
+        let mut byte = [0u8; 1];
+        Ok(self.inner.read(&mut byte)? == 0)
+    }
+}
+
+// Canonical Huffman code, decoded bit by bit as in zlib's puff.c.
+struct Huffman {
+    count: [u16; 16],
+    symbol: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut count = [0u16; 16];
+        for &len in lengths {
+            count[len as usize] += 1;
+        }
+
+        let mut left: i32 = 1;
+        for &c in &count[1..] {
+            left = (left << 1) - c as i32;
+            if left < 0 {
+                return Err(invalid("over-subscribed huffman code"));
+            }
+        }
+
+        let mut offs = [0u16; 16];
+        for len in 1..15 {
+            offs[len + 1] = offs[len] + count[len];
+        }
+        let mut symbol = vec![0u16; lengths.len()];
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbol[offs[len as usize] as usize] = sym as u16;
+                offs[len as usize] += 1;
+            }
+        }
+
+        Ok(Huffman { count, symbol })
+    }
+
+    fn decode(&self, bits: &mut BitReader<impl Read>) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.count[len] as i32;
+            if code - count < first {
+                return Ok(self.symbol[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid huffman code"))
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+enum Block {
+    Stored(usize),
+    Codes(Huffman, Huffman),
+}
+
+/// Streaming deflate decoder that can start at any block given the window
+/// before it, optionally recording a `Z-Map2` block map as it goes.
+pub(crate) struct Inflater<R> {
+    bits: BitReader<R>,
+    window: Vec<u8>,
+    pending: usize,
+    block: Option<Block>,
+    last_block: bool,
+    done: bool,
+    out: u64,
+    map: Option<Vec<ZMapEntry>>,
+    crc: u32,
+}
+
+impl<R: Read> Inflater<R> {
+    pub(crate) fn new(bits: BitReader<R>, out: u64, map: Option<Vec<ZMapEntry>>) -> Self {
+        Inflater {
+            bits,
+            window: Vec::new(),
+            pending: 0,
+            block: None,
+            last_block: false,
+            done: false,
+            out,
+            map,
+            crc: 0,
+        }
+    }
+
+    fn preset_window(&mut self, window: &[u8]) {
+        let start = window.len().saturating_sub(WINDOW_SIZE);
+        self.window = window[start..].to_vec();
+        self.pending = self.window.len();
+    }
+
+    /// CRC-32 of the output read so far.
+    pub(crate) fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    /// The map recorded so far, and the reader positioned after the deflate stream.
+    pub(crate) fn finish(self) -> (Vec<ZMapEntry>, BitReader<R>) {
+        (self.map.unwrap_or_default(), self.bits)
+    }
+
+    fn mark(&mut self, block_start: bool) {
+        let (position, out) = (self.bits.position, self.out);
+        let map = match self.map.as_mut() {
+            Some(map) => map,
+            None => return,
+        };
+
+        match map.last_mut() {
+            Some(last) if last.compressed_bits == position => last.block_start |= block_start,
+            Some(last)
+                if !block_start
+                    && position - last.compressed_bits <= MAX_IN_DELTA
+                    && out - last.uncompressed_offset <= MAX_OUT_DELTA => {}
+            _ => map.push(ZMapEntry {
+                compressed_bits: position,
+                uncompressed_offset: out,
+                block_start,
+            }),
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.window.push(byte);
+        self.out += 1;
+    }
+
+    fn available(&self) -> usize {
+        self.window.len() - self.pending
+    }
+
+    // Decodes until `want` bytes are unread or the stream ends. Decoding no
+    // further than needed keeps reads within the compressed ranges of a map.
+    fn fill(&mut self, want: usize) -> io::Result<()> {
+        while self.available() < want && !self.done {
+            match self.block.take() {
+                None if self.last_block => {
+                    self.mark(true);
+                    self.done = true;
+                }
+                None => {
+                    self.mark(true);
+                    self.block = Some(self.read_block_header()?);
+                }
+                Some(Block::Stored(remaining)) => {
+                    let chunk = remaining.min(want - self.available());
+                    for _ in 0..chunk {
+                        self.mark(false);
+                        let byte = self.bits.bits(8)? as u8;
+                        self.push(byte);
+                    }
+                    if remaining > chunk {
+                        self.block = Some(Block::Stored(remaining - chunk));
+                    }
+                }
+                Some(Block::Codes(lit, dist)) => {
+                    if !self.decode_symbols(&lit, &dist, want)? {
+                        self.block = Some(Block::Codes(lit, dist));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns true once the end of block symbol was decoded.
+    fn decode_symbols(&mut self, lit: &Huffman, dist: &Huffman, want: usize) -> io::Result<bool> {
+        while self.available() < want {
+            self.mark(false);
+            let symbol = lit.decode(&mut self.bits)? as usize;
+            if symbol < 256 {
+                self.push(symbol as u8);
+                continue;
+            } else if symbol == 256 {
+                return Ok(true);
+            }
+
+            let symbol = symbol - 257;
+            if symbol >= LENGTH_BASE.len() {
+                return Err(invalid("invalid length symbol"));
+            }
+            let len = LENGTH_BASE[symbol] as usize
+                + self.bits.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+
+            let symbol = dist.decode(&mut self.bits)? as usize;
+            if symbol >= DIST_BASE.len() {
+                return Err(invalid("invalid distance symbol"));
+            }
+            let distance =
+                DIST_BASE[symbol] as usize + self.bits.bits(DIST_EXTRA[symbol] as u32)? as usize;
+            if distance > self.window.len() {
+                return Err(invalid("distance too far back"));
+            }
+
+            for _ in 0..len {
+                let byte = self.window[self.window.len() - distance];
+                self.push(byte);
+            }
+        }
+        Ok(false)
+    }
+
+    fn read_block_header(&mut self) -> io::Result<Block> {
+        self.last_block = self.bits.bits(1)? == 1;
+
+        match self.bits.bits(2)? {
+            0 => {
+                self.bits.align()?;
+                let len = self.bits.bits(16)?;
+                let nlen = self.bits.bits(16)?;
+                if len != !nlen & 0xffff {
+                    return Err(invalid("stored block length mismatch"));
+                }
+                Ok(Block::Stored(len as usize))
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                Ok(Block::Codes(
+                    Huffman::new(&lengths)?,
+                    Huffman::new(&[5; 30])?,
+                ))
+            }
+            2 => {
+                let nlen = self.bits.bits(5)? as usize + 257;
+                let ndist = self.bits.bits(5)? as usize + 1;
+                let ncode = self.bits.bits(4)? as usize + 4;
+
+                let mut lengths = [0u8; 320];
+                for &index in &CODE_LENGTH_ORDER[..ncode] {
+                    lengths[index] = self.bits.bits(3)? as u8;
+                }
+                let lencode = Huffman::new(&lengths[..19])?;
+
+                let mut index = 0;
+                while index < nlen + ndist {
+                    let symbol = lencode.decode(&mut self.bits)?;
+                    let (value, repeat) = match symbol {
+                        0..=15 => (symbol as u8, 1),
+                        16 if index > 0 => (lengths[index - 1], 3 + self.bits.bits(2)?),
+                        17 => (0, 3 + self.bits.bits(3)?),
+                        18 => (0, 11 + self.bits.bits(7)?),
+                        _ => return Err(invalid("invalid code length repeat")),
+                    };
+                    if index + repeat as usize > nlen + ndist {
+                        return Err(invalid("too many code lengths"));
+                    }
+                    for _ in 0..repeat {
+                        lengths[index] = value;
+                        index += 1;
+                    }
+                }
+
+                Ok(Block::Codes(
+                    Huffman::new(&lengths[..nlen])?,
+                    Huffman::new(&lengths[nlen..nlen + ndist])?,
+                ))
+            }
+            _ => Err(invalid("invalid block type")),
+        }
+    }
+}
+
+impl<R: Read> Read for Inflater<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill(buf.len())?;
+
+        let n = buf.len().min(self.available());
+        buf[..n].copy_from_slice(&self.window[self.pending..self.pending + n]);
+        self.pending += n;
+        self.crc = update_crc(self.crc, &buf[..n]);
+
+        // Keep only the history back-references can reach once it is all read.
+        if self.pending == self.window.len() && self.window.len() > 4 * WINDOW_SIZE {
+            let cut = self.window.len() - WINDOW_SIZE;
+            self.window.drain(..cut);
+            self.pending -= cut;
+        }
+
+        Ok(n)
+    }
+}
+
+/// Checks the gzip trailer after the deflate stream against the CRC-32 and
+/// length of the inflated data, and that no further members follow.
+pub(crate) fn check_gzip_trailer(
+    bits: &mut BitReader<impl Read>,
+    crc: u32,
+    length: u64,
+) -> Result<(), ZsyncError> {
+    bits.align()?;
+    if bits.bits(16)? | bits.bits(16)? << 16 != crc {
+        return Err(ZsyncError::InvalidGzip(String::from(
+            "CRC-32 does not match the gzip trailer",
+        )));
+    }
+    let size = bits.bits(16)? | bits.bits(16)? << 16;
+    if size != length as u32 {
+        return Err(ZsyncError::InvalidGzip(String::from(
+            "uncompressed size does not match the gzip trailer",
+        )));
+    }
+    if !bits.at_end()? {
+        return Err(ZsyncError::InvalidGzip(String::from(
+            "multi-member gzip files are not supported",
+        )));
+    }
+    Ok(())
+}
@@ -1,14 +1,33 @@
 mod util;
 
+pub mod cancel;
+pub mod error;
+pub mod file_checksum;
 pub mod file_maker;
+pub mod gzip;
 pub mod meta_file;
+pub mod zsync_maker;
 
 #[cfg(test)]
 mod tests {
     use std::path::Path;
 
+    use crate::error::ZsyncError;
+    use crate::file_checksum::ChecksumAlgorithm;
     use crate::file_maker::FileMaker;
-    use crate::meta_file::MetaFile;
+    use crate::meta_file::{BlockChecksum, MetaFile};
+    use crate::zsync_maker::{auto_blocksize, auto_hash_lengths, BlockDigests, ZsyncMaker};
+
+    // Reproducible pseudo-random bytes from a linear congruential generator.
+    fn random_bytes(seed: u32, length: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
 
     #[test]
     fn test() {
@@ -23,7 +42,10 @@ mod tests {
         assert!(mf.parse_zsync(Path::new(&file_zsync)).is_ok());
 
         let mut filemaker = FileMaker::new(&mf);
-        let progress = filemaker.map_matcher(Path::new(&file));
+        let report = filemaker
+            .map_matcher(std::fs::File::open(&file).unwrap())
+            .unwrap();
+        let progress = report.progress;
         println!("Caluclated File Completion: {}%", progress);
 
         assert_eq!(progress as u32, 98);
@@ -42,4 +64,941 @@ mod tests {
         assert_eq!(second_part.block_length, 422);
         assert_eq!(second_part.offset, 0);
     }
+
+    #[test]
+    fn malformed_control_file() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let dir = std::env::temp_dir();
+
+        let truncated = dir.join("rs-zsync-truncated.zsync");
+        std::fs::write(&truncated, &data[..data.len() - 10]).unwrap();
+        let mut mf = MetaFile::new();
+        assert!(matches!(
+            mf.parse_zsync(&truncated),
+            Err(ZsyncError::TruncatedChecksums { .. })
+        ));
+
+        let bad_mtime = dir.join("rs-zsync-bad-mtime.zsync");
+        let text = String::from_utf8_lossy(&data).replace("MTime: Sun", "MTime: Xyz");
+        std::fs::write(&bad_mtime, text.as_bytes()).unwrap();
+        let mut mf = MetaFile::new();
+        assert!(matches!(
+            mf.parse_zsync(&bad_mtime),
+            Err(ZsyncError::BadHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_from_bytes() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+
+        let mf = MetaFile::from_bytes(&data).unwrap();
+        assert_eq!(mf.filename, "grad_rebreatherOnLand.pbo");
+        assert_eq!(mf.blocksize, 8192);
+        assert_eq!(mf.length, 1155494);
+        assert_eq!(mf.block_num, 142);
+
+        let from_reader = MetaFile::from_reader(std::io::BufReader::new(&data[..])).unwrap();
+        assert_eq!(from_reader.sha1, mf.sha1);
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+
+        let mut mf = MetaFile::from_bytes(&data).unwrap();
+        assert_eq!(mf.to_bytes().unwrap(), data);
+
+        mf.urls.push(String::from("grad_rebreatherOnLand.pbo"));
+        let rewritten = MetaFile::from_bytes(&mf.to_bytes().unwrap()).unwrap();
+        assert_eq!(rewritten.urls, mf.urls);
+        assert_eq!(rewritten.to_bytes().unwrap(), mf.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn resolve_relative_urls() {
+        let mut mf = MetaFile::new();
+        mf.urls = vec![
+            String::from("grad_rebreatherOnLand.pbo"),
+            String::from("../mirror/grad_rebreatherOnLand.pbo"),
+            String::from("https://example.org/grad_rebreatherOnLand.pbo"),
+        ];
+
+        let urls = mf
+            .resolve_urls("http://a3s.gruppe-adler.de/mods/addons/grad_rebreatherOnLand.pbo.zsync")
+            .unwrap();
+        assert_eq!(
+            urls,
+            vec![
+                "http://a3s.gruppe-adler.de/mods/addons/grad_rebreatherOnLand.pbo",
+                "http://a3s.gruppe-adler.de/mods/mirror/grad_rebreatherOnLand.pbo",
+                "https://example.org/grad_rebreatherOnLand.pbo",
+            ]
+        );
+        assert!(matches!(
+            mf.resolve_urls("not a url"),
+            Err(ZsyncError::InvalidUrl(_))
+        ));
+    }
+
+    #[test]
+    fn keep_extra_headers() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mut patched = b"X-Mod-Name: @gruppe_adler_mod\n".to_vec();
+        patched.extend_from_slice(&data);
+        let mut mf = MetaFile::from_bytes(&patched).unwrap();
+        assert_eq!(mf.extra_header("x-mod-name"), Some("@gruppe_adler_mod"));
+
+        mf.set_extra_header("X-Mod-Version", "1.2.0");
+        let rewritten = MetaFile::from_bytes(&mf.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            rewritten.extra_headers().collect::<Vec<_>>(),
+            vec![
+                ("X-Mod-Name", "@gruppe_adler_mod"),
+                ("X-Mod-Version", "1.2.0")
+            ]
+        );
+
+        mf.remove_extra_header("X-Mod-Name");
+        assert_eq!(mf.extra_header("X-Mod-Name"), None);
+    }
+
+    #[test]
+    fn reject_invalid_headers() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let patch = |from: &str, to: &str| {
+            let pos = data
+                .windows(from.len())
+                .position(|w| w == from.as_bytes())
+                .unwrap();
+            let mut patched = data[..pos].to_vec();
+            patched.extend_from_slice(to.as_bytes());
+            patched.extend_from_slice(&data[pos + from.len()..]);
+            MetaFile::from_bytes(&patched)
+        };
+
+        assert!(matches!(
+            patch("Blocksize: 8192", "Blocksize: 0"),
+            Err(ZsyncError::InvalidBlocksize(0))
+        ));
+        assert!(matches!(
+            patch("Blocksize: 8192", "Blocksize: 3000"),
+            Err(ZsyncError::InvalidBlocksize(3000))
+        ));
+        assert!(matches!(
+            patch("Blocksize: 8192", "Blocksize: 1099511627776"),
+            Err(ZsyncError::InvalidBlocksize(1099511627776))
+        ));
+        assert!(matches!(
+            patch("Hash-Lengths: 2,4,4", "Hash-Lengths: 3,4,4"),
+            Err(ZsyncError::InvalidHashLengths(_))
+        ));
+        assert!(matches!(
+            patch("Hash-Lengths: 2,4,4", "Hash-Lengths: 2,4,2"),
+            Err(ZsyncError::InvalidHashLengths(_))
+        ));
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(matches!(
+            MetaFile::from_bytes(&trailing),
+            Err(ZsyncError::TrailingChecksumData { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_unsafe_filenames() {
+        let mut mf = MetaFile::new();
+
+        mf.filename = String::from("grad_rebreatherOnLand.pbo");
+        assert_eq!(mf.safe_filename().unwrap(), "grad_rebreatherOnLand.pbo");
+
+        for name in [
+            "",
+            "..",
+            "../grad_rebreatherOnLand.pbo",
+            "addons/../../etc/passwd",
+            "/etc/passwd",
+            "C:\\Windows\\system.ini",
+            "con.pbo",
+            "LPT1",
+            "trailing.",
+        ] {
+            mf.filename = String::from(name);
+            assert!(
+                matches!(mf.safe_filename(), Err(ZsyncError::UnsafeFilename(_))),
+                "{:?} should be rejected",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn iterate_block_checksums() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mf = MetaFile::from_bytes(&data).unwrap();
+
+        let blocks: Vec<BlockChecksum> = mf.blocks().collect();
+        assert_eq!(blocks.len(), mf.block_num as usize);
+        assert_eq!(blocks.iter().map(|b| b.length).sum::<usize>(), mf.length);
+
+        let header_len = data.len() - blocks.len() * 8;
+        let first = &blocks[0];
+        assert_eq!((first.seq, first.offset, first.length), (0, 0, 8192));
+        assert_eq!(
+            first.weak.to_be_bytes()[..],
+            data[header_len..header_len + 4]
+        );
+        assert_eq!(first.strong[..], data[header_len + 4..header_len + 8]);
+
+        let last = blocks.last().unwrap();
+        assert_eq!((last.seq, last.offset, last.length), (141, 1155072, 422));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mf = MetaFile::from_bytes(&data).unwrap();
+
+        let json = serde_json::to_string(&mf).unwrap();
+        let restored: MetaFile = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.to_bytes().unwrap(), data);
+
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        value["rsum_bytes"] = 5.into();
+        assert!(serde_json::from_value::<MetaFile>(value).is_err());
+
+        let mut filemaker = FileMaker::new(&mf);
+        let seed = std::fs::File::open("test-data/grad_rebreatherOnLand.pbo").unwrap();
+        filemaker.map_matcher(seed).unwrap();
+        let parts = filemaker.file_maker();
+        let json = serde_json::to_string(&parts).unwrap();
+        let restored: Vec<crate::file_maker::FilePart> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored[1].start_offset, parts[1].start_offset);
+    }
+
+    #[test]
+    fn verify_whole_file_checksums() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mut mf = MetaFile::from_bytes(&data).unwrap();
+        let target = std::fs::read("test-data/grad_rebreatherOnLand.pbo").unwrap();
+
+        assert_eq!(
+            mf.strongest_checksum().unwrap().algorithm,
+            ChecksumAlgorithm::Sha1
+        );
+        assert!(matches!(
+            mf.verify_file(&target[..]),
+            Err(ZsyncError::ChecksumMismatch { .. })
+        ));
+
+        mf.sha1.clear();
+        assert!(matches!(
+            mf.verify_file(&target[..]),
+            Err(ZsyncError::MissingChecksum)
+        ));
+
+        mf.md5 = String::from("D41D8CD98F00B204E9800998ECF8427E");
+        mf.sha256 =
+            String::from("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        let rewritten = MetaFile::from_bytes(&mf.to_bytes().unwrap()).unwrap();
+        assert_eq!(rewritten.file_checksums().len(), 2);
+        assert!(rewritten.verify_file(&b""[..]).is_ok());
+    }
+
+    #[test]
+    fn make_control_file() {
+        let file = Path::new("test-data/grad_rebreatherOnLand.pbo");
+        let target = std::fs::read(file).unwrap();
+
+        let mut maker = ZsyncMaker::new();
+        maker.urls.push(String::from("grad_rebreatherOnLand.pbo"));
+        let mf = maker.make(file).unwrap();
+        assert_eq!(mf.filename, "grad_rebreatherOnLand.pbo");
+        assert_eq!(mf.length, target.len());
+        assert!(mf.verify_file(&target[..]).is_ok());
+
+        let bytes = mf.to_bytes().unwrap();
+        let parsed = MetaFile::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.to_bytes().unwrap(), bytes);
+
+        let mut filemaker = FileMaker::new(&parsed);
+        let report = filemaker
+            .map_matcher(std::io::Cursor::new(&target))
+            .unwrap();
+        assert_eq!(report.progress as u32, 100);
+        assert_eq!(report.matched_blocks, report.total_blocks);
+    }
+
+    #[test]
+    fn pick_blocksize_and_hash_lengths() {
+        assert_eq!(auto_blocksize(1155494), 2048);
+        assert_eq!(auto_blocksize(5_000_000_000), 4096);
+        assert_eq!(auto_hash_lengths(1155494, 2048), (2, 2, 4));
+        assert_eq!(auto_hash_lengths(5_000_000_000, 4096), (2, 3, 6));
+        assert_eq!(auto_hash_lengths(1000, 2048), (1, 2, 4));
+        assert_eq!(auto_hash_lengths(0, 2048), (1, 2, 3));
+
+        let file = Path::new("test-data/grad_rebreatherOnLand.pbo");
+        let mf = ZsyncMaker::new().make(file).unwrap();
+        assert_eq!(mf.blocksize, 2048);
+        assert_eq!((mf.seq_num, mf.rsum_bytes, mf.checksum_bytes), (2, 2, 4));
+
+        let mut maker = ZsyncMaker::new();
+        maker.blocksize = Some(8192);
+        maker.checksum_bytes = Some(16);
+        let mf = maker.make(file).unwrap();
+        assert_eq!(mf.blocksize, 8192);
+        assert_eq!(mf.checksum_bytes, 16);
+    }
+
+    #[test]
+    fn make_control_file_from_stream() {
+        let file = Path::new("test-data/grad_rebreatherOnLand.pbo");
+        let target = std::fs::read(file).unwrap();
+
+        let mut maker = ZsyncMaker::new();
+        maker.filename = Some(String::from("grad_rebreatherOnLand.pbo"));
+        let streamed = maker.make_from_reader(&target[..]).unwrap();
+        let mut from_file = maker.make(file).unwrap();
+
+        assert_eq!(streamed.length, target.len());
+        assert_eq!(
+            (
+                streamed.seq_num,
+                streamed.rsum_bytes,
+                streamed.checksum_bytes
+            ),
+            (2, 2, 4)
+        );
+        from_file.m_time = streamed.m_time;
+        assert_eq!(streamed.to_bytes().unwrap(), from_file.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn make_control_file_in_parallel() {
+        let file = Path::new("test-data/grad_rebreatherOnLand.pbo");
+
+        let mut maker = ZsyncMaker::new();
+        let serial = maker.make(file).unwrap().to_bytes().unwrap();
+        for threads in [1, 3, 8, 1000] {
+            let parallel = maker.make_parallel(file, threads).unwrap();
+            assert_eq!(parallel.to_bytes().unwrap(), serial);
+        }
+
+        maker.blocksize = Some(8192);
+        let serial = maker.make(file).unwrap().to_bytes().unwrap();
+        let parallel = maker.make_parallel(file, 4).unwrap();
+        assert_eq!(parallel.to_bytes().unwrap(), serial);
+
+        // Files too short to give every thread a chunk.
+        let data = std::fs::read(file).unwrap();
+        maker.blocksize = Some(2048);
+        for (length, threads) in [(3 * 2048 + 100, 3), (5 * 2048, 4)] {
+            let short = std::env::temp_dir().join(format!("rs-zsync-chunks-{}.pbo", length));
+            std::fs::write(&short, &data[..length]).unwrap();
+            let serial = maker.make(&short).unwrap().to_bytes().unwrap();
+            let parallel = maker.make_parallel(&short, threads).unwrap();
+            assert_eq!(parallel.to_bytes().unwrap(), serial);
+        }
+
+        let empty = std::env::temp_dir().join("rs-zsync-empty.pbo");
+        std::fs::write(&empty, b"").unwrap();
+        let parallel = maker.make_parallel(&empty, 4).unwrap();
+        assert_eq!((parallel.length, parallel.block_num), (0, 0));
+    }
+
+    #[test]
+    fn make_control_files_for_directory() {
+        let dir = std::env::temp_dir().join("rs-zsync-batch");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("addons")).unwrap();
+        std::fs::copy(
+            "test-data/grad_rebreatherOnLand.pbo",
+            dir.join("addons/grad_rebreatherOnLand.pbo"),
+        )
+        .unwrap();
+        std::fs::write(dir.join("mod.cpp"), b"name = \"grad\";").unwrap();
+
+        let maker = ZsyncMaker::new();
+        let summary = maker.make_dir(&dir).unwrap();
+        assert_eq!(
+            summary.generated,
+            vec![
+                dir.join("addons/grad_rebreatherOnLand.pbo"),
+                dir.join("mod.cpp")
+            ]
+        );
+        assert!(summary.skipped.is_empty());
+
+        let control = dir.join("addons/grad_rebreatherOnLand.pbo.zsync");
+        let mf = MetaFile::from_bytes(&std::fs::read(&control).unwrap()).unwrap();
+        assert_eq!(mf.filename, "grad_rebreatherOnLand.pbo");
+        assert_eq!(mf.urls, vec!["grad_rebreatherOnLand.pbo"]);
+
+        std::fs::write(dir.join("mod.cpp"), b"name = \"grad_mod\";").unwrap();
+        let summary = maker.make_dir(&dir).unwrap();
+        assert_eq!(summary.generated, vec![dir.join("mod.cpp")]);
+        assert_eq!(
+            summary.skipped,
+            vec![dir.join("addons/grad_rebreatherOnLand.pbo")]
+        );
+        assert!(summary.failed.is_empty());
+
+        // A control file that cannot be written does not stop the others.
+        std::fs::write(dir.join("addons/broken.pbo"), b"broken").unwrap();
+        std::fs::create_dir(dir.join("addons/broken.pbo.zsync")).unwrap();
+        std::fs::write(dir.join("mod.cpp"), b"name = \"grad\";").unwrap();
+        let mut maker = ZsyncMaker::new();
+        maker.urls = vec!["https://mirror.example/@grad/".to_string()];
+        let summary = maker.make_dir(&dir).unwrap();
+        assert_eq!(summary.generated, vec![dir.join("mod.cpp")]);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, dir.join("addons/broken.pbo"));
+        assert!(matches!(summary.failed[0].1, ZsyncError::Io(_)));
+
+        std::fs::remove_file(&control).unwrap();
+        let summary = maker.make_dir(&dir).unwrap();
+        assert_eq!(
+            summary.generated,
+            vec![dir.join("addons/grad_rebreatherOnLand.pbo")]
+        );
+        let mf = MetaFile::from_bytes(&std::fs::read(&control).unwrap()).unwrap();
+        assert_eq!(mf.filename, "grad_rebreatherOnLand.pbo");
+        assert_eq!(
+            mf.urls,
+            vec!["https://mirror.example/@grad/addons/grad_rebreatherOnLand.pbo"]
+        );
+    }
+
+    #[test]
+    fn make_control_file_for_gzip() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        use crate::file_maker::FilePart;
+
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo").unwrap();
+        let plain = ZsyncMaker::new()
+            .make(Path::new("test-data/grad_rebreatherOnLand.pbo"))
+            .unwrap();
+
+        for level in [0, 6, 9] {
+            let gz = std::env::temp_dir().join(format!("rs-zsync-{}.pbo.gz", level));
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+            encoder.write_all(&data).unwrap();
+            std::fs::write(&gz, encoder.finish().unwrap()).unwrap();
+
+            let mf = ZsyncMaker::new().make_gzip(&gz).unwrap();
+            assert_eq!(mf.filename, format!("rs-zsync-{}.pbo", level));
+            assert_eq!(mf.z_filename, format!("rs-zsync-{}.pbo.gz", level));
+            assert_eq!(mf.sha1, plain.sha1);
+            assert_eq!(mf.length, data.len());
+            mf.verify_file(&data[..]).unwrap();
+
+            let bytes = mf.to_bytes().unwrap();
+            let parsed = MetaFile::from_bytes(&bytes).unwrap();
+            assert_eq!(parsed.z_map, mf.z_map);
+            assert_eq!(parsed.recompress, mf.recompress);
+            assert_eq!(parsed.to_bytes().unwrap(), bytes);
+
+            let (header, _) = parsed.recompress_options().unwrap();
+            assert_eq!(header[..3], [0x1f, 0x8b, 0x08]);
+
+            let compressed = std::fs::read(&gz).unwrap();
+            let parts = [(0, 2048), (500_000, 502_048), (1_150_000, data.len())];
+            let parts: Vec<FilePart> = parts
+                .iter()
+                .map(|&(start_offset, end_offset)| FilePart {
+                    start_offset,
+                    end_offset,
+                    block_length: end_offset - start_offset,
+                    offset: start_offset,
+                })
+                .collect();
+
+            let ranges = parsed.compressed_ranges(&parts);
+            assert_eq!(ranges.len(), parts.len());
+            for range in &ranges {
+                let start = range.compressed_start as usize;
+                let end = range.compressed_end.unwrap() as usize;
+                let window_end = range.window_end as usize;
+                let window = &data[window_end.saturating_sub(32768)..window_end];
+
+                let out = parsed
+                    .inflate_range(range, &compressed[start..end], window)
+                    .unwrap();
+                let expected =
+                    &data[range.uncompressed_start as usize..range.uncompressed_end as usize];
+                assert!(out == expected);
+            }
+
+            let count = format!("Z-Map2: {}\n", parsed.z_map.len());
+            let pos = bytes
+                .windows(count.len())
+                .position(|w| w == count.as_bytes())
+                .unwrap();
+            for huge in ["Z-Map2: 4611686018427387904\n", "Z-Map2: 1000000000\n"] {
+                let mut patched = bytes[..pos].to_vec();
+                patched.extend_from_slice(huge.as_bytes());
+                patched.extend_from_slice(&bytes[pos + count.len()..]);
+                assert!(matches!(
+                    MetaFile::from_bytes(&patched),
+                    Err(ZsyncError::BadHeader { .. })
+                ));
+            }
+
+            let mut corrupted = compressed.clone();
+            let crc = corrupted.len() - 8;
+            corrupted[crc] ^= 0xff;
+            std::fs::write(&gz, &corrupted).unwrap();
+            assert!(matches!(
+                ZsyncMaker::new().make_gzip(&gz),
+                Err(ZsyncError::InvalidGzip(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn regenerate_after_local_edit() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo").unwrap();
+        let original = std::env::temp_dir().join("rs-zsync-incremental-old.pbo");
+        let edited = std::env::temp_dir().join("rs-zsync-incremental-new.pbo");
+        std::fs::write(&original, &data).unwrap();
+        let mut changed = data.clone();
+        changed[600_000..600_100].fill(0x5a);
+        std::fs::write(&edited, &changed).unwrap();
+
+        let maker = ZsyncMaker::new();
+        let (previous, digests) = maker.make_with_digests(&original).unwrap();
+        assert_eq!(
+            previous.to_bytes().unwrap(),
+            maker.make(&original).unwrap().to_bytes().unwrap()
+        );
+        let digests = BlockDigests::from_bytes(&digests.to_bytes()).unwrap();
+        assert_eq!(digests.digests.len(), previous.block_num as usize);
+
+        let full = maker.make(&edited).unwrap();
+        let (incremental, new_digests) = maker
+            .make_incremental(&previous, &digests, &edited)
+            .unwrap();
+        assert_eq!(incremental.to_bytes().unwrap(), full.to_bytes().unwrap());
+        assert_eq!(new_digests, maker.make_with_digests(&edited).unwrap().1);
+
+        // Digests of another version of the file are not trusted.
+        let (incremental, _) = maker
+            .make_incremental(&previous, &new_digests, &edited)
+            .unwrap();
+        assert_eq!(incremental.to_bytes().unwrap(), full.to_bytes().unwrap());
+
+        // Short sums leave an edit with an unchanged truncated rsum likely,
+        // which must still be rehashed.
+        let mut maker = ZsyncMaker::new();
+        maker.seq_matches = Some(2);
+        maker.rsum_bytes = Some(2);
+        maker.checksum_bytes = Some(4);
+        let mut changed = data.clone();
+        changed[20480] ^= 0x20;
+        std::fs::write(&edited, &changed).unwrap();
+
+        let (previous, digests) = maker.make_with_digests(&original).unwrap();
+        let (incremental, _) = maker
+            .make_incremental(&previous, &digests, &edited)
+            .unwrap();
+        let full = maker.make(&edited).unwrap();
+        assert_eq!(incremental.to_bytes().unwrap(), full.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn match_against_any_seed() {
+        use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mf = MetaFile::from_bytes(&data).unwrap();
+        let seed = std::fs::read("test-data/grad_rebreatherOnLand.pbo").unwrap();
+
+        let mut from_file = FileMaker::new(&mf);
+        let file = std::fs::File::open("test-data/grad_rebreatherOnLand.pbo").unwrap();
+        let file_report = from_file.map_matcher(file).unwrap();
+        let mut from_memory = FileMaker::new(&mf);
+        let memory_report = from_memory.map_matcher(Cursor::new(&seed)).unwrap();
+        assert_eq!(memory_report, file_report);
+        assert_eq!(memory_report.seed_length, seed.len() as u64);
+        assert_eq!(memory_report.total_blocks, mf.block_num as usize);
+
+        // Claims more data than it delivers.
+        struct Truncated(Cursor<Vec<u8>>);
+        impl Read for Truncated {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+        impl Seek for Truncated {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                match pos {
+                    SeekFrom::End(_) => Ok(10_000_000),
+                    _ => self.0.seek(pos),
+                }
+            }
+        }
+
+        let mut filemaker = FileMaker::new(&mf);
+        match filemaker.map_matcher(Truncated(Cursor::new(seed))) {
+            Err(ZsyncError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("expected an i/o error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn match_against_several_seeds() {
+        use std::io::Cursor;
+
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mf = MetaFile::from_bytes(&data).unwrap();
+        let seed = std::fs::read("test-data/grad_rebreatherOnLand.pbo").unwrap();
+        let (front, back) = (&seed[..600_000], &seed[500_000..]);
+
+        let mut filemaker = FileMaker::new(&mf);
+        let first = filemaker.map_matcher(Cursor::new(front)).unwrap();
+        let second = filemaker.map_matcher(Cursor::new(back)).unwrap();
+        assert_eq!((first.seed, second.seed), (0, 1));
+        assert!(first.new_blocks > 0 && second.new_blocks > 0);
+        assert_eq!(first.new_blocks + second.new_blocks, second.matched_blocks);
+
+        let mut whole = FileMaker::new(&mf);
+        let whole = whole.map_matcher(Cursor::new(&seed)).unwrap();
+        assert_eq!(second.matched_blocks, whole.matched_blocks);
+
+        for (index, source) in second.sources.iter().enumerate() {
+            let source = match source {
+                Some(source) => source,
+                None => continue,
+            };
+            let seed_len = [front.len(), back.len()][source.seed] as u64;
+            assert!(source.offset < seed_len);
+            assert_eq!(first.sources[index].is_some(), source.seed == 0);
+        }
+
+        let again = filemaker.map_matcher(Cursor::new(front)).unwrap();
+        assert_eq!(again.seed, 2);
+        assert_eq!(again.new_blocks, 0);
+        assert_eq!(again.sources, second.sources);
+    }
+
+    #[test]
+    fn require_consecutive_matches() {
+        use std::io::Cursor;
+
+        let data = random_bytes(1, 8 * 2048);
+        let block = |i: usize| &data[i * 2048..(i + 1) * 2048];
+
+        let mut maker = ZsyncMaker::new();
+        maker.blocksize = Some(2048);
+        maker.seq_matches = Some(2);
+        let mf = maker.make_from_reader(&data[..]).unwrap();
+        let found = |seed: Vec<u8>| {
+            let mut filemaker = FileMaker::new(&mf);
+            let report = filemaker.map_matcher(Cursor::new(seed)).unwrap();
+            report
+                .sources
+                .iter()
+                .enumerate()
+                .filter_map(|(i, source)| source.map(|s| (i, s.offset)))
+                .collect::<Vec<_>>()
+        };
+
+        // Neighbouring target blocks found apart from each other in the seed.
+        let apart = [block(1), &[0u8; 100], block(0)].concat();
+        assert_eq!(found(apart), vec![]);
+        assert_eq!(found(block(3).to_vec()), vec![]);
+
+        // A run that ends with the last block of the target.
+        let run = [&[7u8; 7], block(5), block(6), block(7)].concat();
+        assert_eq!(found(run), vec![(5, 7), (6, 2055), (7, 4103)]);
+
+        // A run needs only its first two blocks confirmed together.
+        let run = [block(2), block(3), block(4), &[0u8; 300], block(6)].concat();
+        assert_eq!(found(run), vec![(2, 0), (3, 2048), (4, 4096)]);
+    }
+
+    #[test]
+    fn match_against_seed_slice() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mf = MetaFile::from_bytes(&data).unwrap();
+        let seed = std::fs::read("test-data/grad_rebreatherOnLand.pbo").unwrap();
+
+        for slice in [&seed[..], &seed[1_155_000..], &seed[3..], &[]] {
+            let mut buffered = FileMaker::new(&mf);
+            let expected = buffered.map_matcher(std::io::Cursor::new(slice)).unwrap();
+            let mut sliced = FileMaker::new(&mf);
+            assert_eq!(sliced.map_matcher_slice(slice).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn rolling_sum_past_two_gibibytes() {
+        use crate::util::rsum::Rsum;
+
+        let block: Vec<u8> = (0..2048u32).map(|i| (i * 7 + i / 5) as u8).collect();
+        // Zeroed pages stay unallocated until the block is written.
+        let offset = (1usize << 31) + 100;
+        let mut seed = vec![0u8; offset + 2048];
+        seed[offset..].copy_from_slice(&block);
+
+        let mut far = Rsum::new();
+        far.first(&seed, offset, 2048);
+        let mut near = Rsum::new();
+        near.first(&block, 0, 2048);
+        assert_eq!(far.get_value(), near.get_value());
+    }
+
+    #[test]
+    #[ignore = "scans a 2 GiB seed"]
+    fn match_past_two_gibibytes_in_slice() {
+        let block: Vec<u8> = (0..2048u32).map(|i| (i * 7 + i / 5) as u8).collect();
+        let mut maker = ZsyncMaker::new();
+        maker.blocksize = Some(2048);
+        maker.seq_matches = Some(1);
+        let mf = maker.make_from_reader(&block[..]).unwrap();
+
+        // Zeroed pages stay unallocated until the block is written.
+        let offset = (1usize << 31) + 100;
+        let mut seed = vec![0u8; offset + 4096];
+        seed[offset..offset + 2048].copy_from_slice(&block);
+
+        let mut filemaker = FileMaker::new(&mf);
+        let report = filemaker.map_matcher_slice(&seed).unwrap();
+        assert_eq!(report.matched_blocks, 1);
+        assert_eq!(report.sources[0].unwrap().offset, offset as u64);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn match_against_mapped_seed() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mf = MetaFile::from_bytes(&data).unwrap();
+        let seed = std::fs::read("test-data/grad_rebreatherOnLand.pbo").unwrap();
+
+        let short = std::env::temp_dir().join("rs-zsync-mmap-short.pbo");
+        let empty = std::env::temp_dir().join("rs-zsync-mmap-empty.pbo");
+        std::fs::write(&short, &seed[1_155_000..]).unwrap();
+        std::fs::write(&empty, b"").unwrap();
+
+        for path in [
+            Path::new("test-data/grad_rebreatherOnLand.pbo"),
+            &short,
+            &empty,
+        ] {
+            let mut buffered = FileMaker::new(&mf);
+            let expected = buffered
+                .map_matcher(std::fs::File::open(path).unwrap())
+                .unwrap();
+            let seed = std::fs::File::open(path).unwrap();
+            let mut mapped = FileMaker::new(&mf);
+            // SAFETY: the test files are not modified while mapped.
+            let report = unsafe { mapped.map_matcher_mmap(&seed) }.unwrap();
+            assert_eq!(report, expected);
+        }
+    }
+
+    #[test]
+    fn match_in_parallel() {
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mf = MetaFile::from_bytes(&data).unwrap();
+        let file = Path::new("test-data/grad_rebreatherOnLand.pbo");
+        let seed = std::fs::read(file).unwrap();
+
+        // Repeated and shifted content, so matches sit close to the segment
+        // boundaries and continue across them.
+        let shuffled = std::env::temp_dir().join("rs-zsync-parallel.pbo");
+        std::fs::write(
+            &shuffled,
+            [&seed[700_001..], &seed[..300_000], &seed[299_000..700_000]].concat(),
+        )
+        .unwrap();
+
+        let mut maker = ZsyncMaker::new();
+        maker.seq_matches = Some(1);
+        let single = maker.make(file).unwrap();
+
+        for (mf, path) in [(&mf, file), (&mf, &shuffled), (&single, &shuffled)] {
+            let mut serial = FileMaker::new(mf);
+            let expected = serial
+                .map_matcher(std::fs::File::open(path).unwrap())
+                .unwrap();
+            for threads in [1, 2, 3, 8, 64] {
+                let mut parallel = FileMaker::new(mf);
+                let report = parallel.map_matcher_parallel(path, threads).unwrap();
+                assert_eq!(report, expected);
+            }
+        }
+
+        // Seeds too short to give every thread a segment.
+        for (length, threads) in [(5, 4), (10, 8), (100, 16)] {
+            let short = std::env::temp_dir().join(format!("rs-zsync-parallel-{}.pbo", length));
+            std::fs::write(&short, &seed[..length]).unwrap();
+            let mut serial = FileMaker::new(&mf);
+            let expected = serial
+                .map_matcher(std::fs::File::open(&short).unwrap())
+                .unwrap();
+            let mut parallel = FileMaker::new(&mf);
+            let report = parallel.map_matcher_parallel(&short, threads).unwrap();
+            assert_eq!(report, expected);
+        }
+
+        let empty = std::env::temp_dir().join("rs-zsync-parallel-empty.pbo");
+        std::fs::write(&empty, b"").unwrap();
+        let mut parallel = FileMaker::new(&mf);
+        let report = parallel.map_matcher_parallel(&empty, 4).unwrap();
+        assert_eq!(report.matched_blocks, 0);
+    }
+
+    #[test]
+    fn report_scan_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mf = MetaFile::from_bytes(&data).unwrap();
+        let file = Path::new("test-data/grad_rebreatherOnLand.pbo");
+        let seed_length = std::fs::metadata(file).unwrap().len();
+
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let mut filemaker = FileMaker::new(&mf);
+        let sink = Arc::clone(&updates);
+        filemaker
+            .set_progress_callback(move |progress| sink.lock().unwrap().push(progress.clone()));
+
+        let report = filemaker
+            .map_matcher(std::fs::File::open(file).unwrap())
+            .unwrap();
+        let updates = std::mem::take(&mut *updates.lock().unwrap());
+        assert!(updates.len() > 1);
+        assert!(updates
+            .windows(2)
+            .all(|w| w[0].bytes_scanned <= w[1].bytes_scanned
+                && w[0].matched_blocks <= w[1].matched_blocks
+                && w[0].elapsed <= w[1].elapsed));
+        let last = updates.last().unwrap();
+        assert_eq!(
+            (last.bytes_scanned, last.total_bytes),
+            (seed_length, seed_length)
+        );
+        assert_eq!(last.matched_blocks, report.matched_blocks);
+
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let mut filemaker = FileMaker::new(&mf);
+        let sink = Arc::clone(&updates);
+        filemaker
+            .set_progress_callback(move |progress| sink.lock().unwrap().push(progress.clone()));
+        let report = filemaker.map_matcher_parallel(file, 4).unwrap();
+        let last = updates.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(last.bytes_scanned, seed_length);
+        assert_eq!(last.matched_blocks, report.matched_blocks);
+    }
+
+    #[test]
+    fn cancel_scans_and_generation() {
+        use crate::cancel::CancellationToken;
+
+        let data = std::fs::read("test-data/grad_rebreatherOnLand.pbo.zsync").unwrap();
+        let mf = MetaFile::from_bytes(&data).unwrap();
+        let file = Path::new("test-data/grad_rebreatherOnLand.pbo");
+
+        let mut full = FileMaker::new(&mf);
+        let expected = full
+            .map_matcher(std::fs::File::open(file).unwrap())
+            .unwrap();
+
+        // Cancelled from the progress callback after the first read window.
+        let token = CancellationToken::new();
+        let mut filemaker = FileMaker::new(&mf);
+        filemaker.set_cancellation_token(token.clone());
+        let canceller = token.clone();
+        filemaker.set_progress_callback(move |_| canceller.cancel());
+        match filemaker.map_matcher(std::fs::File::open(file).unwrap()) {
+            Err(ZsyncError::Cancelled) => {}
+            other => panic!("expected cancellation, got {:?}", other),
+        }
+        let partial = filemaker.block_sources();
+        assert!(partial.iter().any(Option::is_some));
+        assert!(partial.iter().filter(|s| s.is_some()).count() < expected.matched_blocks);
+
+        filemaker.clear_progress_callback();
+        token.reset();
+        let resumed = filemaker
+            .map_matcher(std::fs::File::open(file).unwrap())
+            .unwrap();
+        assert_eq!(resumed.matched_blocks, expected.matched_blocks);
+        let offsets = |sources: &[Option<crate::file_maker::BlockSource>]| {
+            sources
+                .iter()
+                .map(|s| s.map(|s| s.offset))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(offsets(&resumed.sources), offsets(&expected.sources));
+
+        token.cancel();
+        let mut filemaker = FileMaker::new(&mf);
+        filemaker.set_cancellation_token(token.clone());
+        assert!(matches!(
+            filemaker.map_matcher_parallel(file, 4),
+            Err(ZsyncError::Cancelled)
+        ));
+
+        let mut maker = ZsyncMaker::new();
+        maker.cancel = Some(token);
+        assert!(matches!(maker.make(file), Err(ZsyncError::Cancelled)));
+        assert!(matches!(
+            maker.make_parallel(file, 4),
+            Err(ZsyncError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn fill_identical_blocks() {
+        use std::io::Cursor;
+
+        let random = random_bytes(7, 4 * 2048);
+        let (r0, r1, r2, r3) = (
+            &random[..2048],
+            &random[2048..4096],
+            &random[4096..6144],
+            &random[6144..],
+        );
+        let zero = vec![0u8; 2048];
+        let data = [r0, &zero, &zero, &zero, r1, r2, &zero, r3].concat();
+
+        let mut maker = ZsyncMaker::new();
+        maker.blocksize = Some(2048);
+        for (seq_matches, seed, offset) in [(1, zero.clone(), 0), (2, [r0, &zero].concat(), 2048)] {
+            maker.seq_matches = Some(seq_matches);
+            let mf = maker.make_from_reader(&data[..]).unwrap();
+
+            let mut filemaker = FileMaker::new(&mf);
+            let report = filemaker.map_matcher(Cursor::new(&seed)).unwrap();
+
+            let path = std::env::temp_dir().join(format!("rs-zsync-identical-{}", seq_matches));
+            std::fs::write(&path, &seed).unwrap();
+            for threads in [1, 3, 7] {
+                let mut parallel = FileMaker::new(&mf);
+                assert_eq!(
+                    parallel.map_matcher_parallel(&path, threads).unwrap(),
+                    report
+                );
+            }
+
+            let filled: Vec<_> = report
+                .sources
+                .iter()
+                .enumerate()
+                .filter_map(|(i, source)| source.map(|s| (i, s.offset)))
+                .filter(|&(i, _)| i != 0)
+                .collect();
+            assert_eq!(
+                filled,
+                vec![(1, offset), (2, offset), (3, offset), (6, offset)]
+            );
+        }
+    }
 }
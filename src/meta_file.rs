@@ -1,12 +1,40 @@
-use std::error::Error;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::{fs::File, path::Path};
 
-use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use chrono::{DateTime, FixedOffset, TimeZone};
+use url::Url;
 
+use crate::error::ZsyncError;
+use crate::file_checksum::{self, ChecksumAlgorithm, FileChecksum};
+use crate::gzip::{self, ZMapEntry};
 use crate::util::{chaininghash::ChainingHash, checksumpair::ChecksumPair};
 
+/// Largest `Blocksize` accepted, well above anything `zsyncmake` picks.
+pub const MAX_BLOCKSIZE: usize = 1 << 24;
+
+/// Whether `blocksize` is a power of two no larger than [`MAX_BLOCKSIZE`].
+pub fn is_valid_blocksize(blocksize: usize) -> bool {
+    blocksize.is_power_of_two() && blocksize <= MAX_BLOCKSIZE
+}
+
+/// Checksums of a single block of the target file, as listed in the control file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockChecksum {
+    pub seq: u32,
+    pub offset: u64,
+    pub length: usize,
+    /// The rsum truncated to `rsum_bytes`, read as a big-endian integer.
+    pub weak: u32,
+    pub strong: Vec<u8>,
+}
+
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "MetaFileRepr", try_from = "MetaFileRepr")
+)]
 pub struct MetaFile {
     pub zsync: String,
     pub filename: String,
@@ -17,8 +45,18 @@ pub struct MetaFile {
     pub rsum_bytes: u32,
     pub checksum_bytes: u32,
     pub block_num: u32,
-    pub url: String,
+    pub urls: Vec<String>,
     pub sha1: String,
+    pub md5: String,
+    pub sha256: String,
+    /// Name of the gzip-compressed file the content is fetched from, if any.
+    pub z_filename: String,
+    pub z_urls: Vec<String>,
+    /// Original gzip header in hex, followed by the `gzip` options needed to
+    /// recompress the content into an identical file.
+    pub recompress: String,
+    pub z_map: Vec<ZMapEntry>,
+    extra_headers: Vec<(String, String)>,
 
     pub hashtable: ChainingHash,
 }
@@ -28,14 +66,20 @@ impl MetaFile {
         MetaFile {
             zsync: String::new(),
             filename: String::new(),
-            m_time: DateTime::<FixedOffset>::from_utc(
-                NaiveDateTime::from_timestamp(0, 0),
-                FixedOffset::east(0),
-            ),
+            m_time: FixedOffset::east_opt(0)
+                .and_then(|tz| tz.timestamp_opt(0, 0).single())
+                .expect("unix epoch is representable"),
             blocksize: 0,
             length: 0,
-            url: String::new(),
+            urls: Vec::new(),
             sha1: String::new(),
+            md5: String::new(),
+            sha256: String::new(),
+            z_filename: String::new(),
+            z_urls: Vec::new(),
+            recompress: String::new(),
+            z_map: Vec::new(),
+            extra_headers: Vec::new(),
             seq_num: 0,
             rsum_bytes: 0,
             checksum_bytes: 0,
@@ -44,67 +88,325 @@ impl MetaFile {
         }
     }
 
-    pub fn parse_zsync(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+    pub fn from_reader(reader: impl BufRead) -> Result<MetaFile, ZsyncError> {
+        let mut mf = MetaFile::new();
+        mf.parse_reader(reader)?;
+        Ok(mf)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MetaFile, ZsyncError> {
+        MetaFile::from_reader(bytes)
+    }
+
+    pub fn parse_zsync(&mut self, path: &Path) -> Result<(), ZsyncError> {
         let file = File::open(path)?;
-        let mut br = BufReader::new(file);
+        self.parse_reader(BufReader::new(file))
+    }
+
+    /// Whole-file digests present in the control file, strongest first.
+    pub fn file_checksums(&self) -> Vec<FileChecksum> {
+        [
+            (ChecksumAlgorithm::Sha256, &self.sha256),
+            (ChecksumAlgorithm::Sha1, &self.sha1),
+            (ChecksumAlgorithm::Md5, &self.md5),
+        ]
+        .iter()
+        .filter(|(_, hex)| !hex.is_empty())
+        .map(|(algorithm, hex)| FileChecksum::new(*algorithm, hex))
+        .collect()
+    }
+
+    pub fn strongest_checksum(&self) -> Option<FileChecksum> {
+        self.file_checksums().into_iter().next()
+    }
+
+    /// Hashes `reader` once and checks it against every whole-file digest present.
+    pub fn verify_file(&self, reader: impl Read) -> Result<(), ZsyncError> {
+        file_checksum::verify_all(&self.file_checksums(), reader)
+    }
+
+    /// Block checksums in target file order.
+    pub fn blocks(&self) -> impl Iterator<Item = BlockChecksum> + '_ {
+        self.sorted_pairs().into_iter().map(move |pair| {
+            let offset = pair.seq as u64 * self.blocksize as u64;
+            let weak = pack_weak_sum(pair.weak)[..self.rsum_bytes as usize]
+                .iter()
+                .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+
+            BlockChecksum {
+                seq: pair.seq as u32,
+                offset,
+                length: self
+                    .blocksize
+                    .min(self.length.saturating_sub(offset as usize)),
+                weak,
+                strong: pair.strong.clone(),
+            }
+        })
+    }
+
+    fn sorted_pairs(&self) -> Vec<&ChecksumPair> {
+        let mut pairs: Vec<&ChecksumPair> = self.hashtable.iter().collect();
+        pairs.sort_by_key(|p| p.seq);
+        pairs
+    }
+
+    /// Returns `filename` if it is safe to join onto an output directory.
+    ///
+    /// Path separators, drive prefixes, `.`/`..`, control characters and
+    /// names Windows reserves for devices are all rejected.
+    pub fn safe_filename(&self) -> Result<&str, ZsyncError> {
+        let name = self.filename.as_str();
+        let unsafe_name = || ZsyncError::UnsafeFilename(name.to_string());
+
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(unsafe_name());
+        }
+        if name
+            .chars()
+            .any(|c| matches!(c, '/' | '\\' | ':') || c.is_control())
+        {
+            return Err(unsafe_name());
+        }
+        if name.ends_with('.') || name.ends_with(' ') {
+            return Err(unsafe_name());
+        }
+
+        let stem = name.split('.').next().unwrap_or(name).to_ascii_uppercase();
+        let reserved = match stem.as_str() {
+            "CON" | "PRN" | "AUX" | "NUL" => true,
+            s if s.len() == 4 && (s.starts_with("COM") || s.starts_with("LPT")) => {
+                matches!(s.as_bytes()[3], b'1'..=b'9')
+            }
+            _ => false,
+        };
+        if reserved {
+            return Err(unsafe_name());
+        }
+
+        Ok(name)
+    }
+
+    /// Headers this crate does not interpret, in the order they were read.
+    pub fn extra_headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.extra_headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn extra_header(&self, name: &str) -> Option<&str> {
+        self.extra_headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replaces the value of an existing header with the same name, or appends a new one.
+    pub fn set_extra_header(&mut self, name: &str, value: &str) {
+        match self
+            .extra_headers
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some(header) => header.1 = value.to_string(),
+            None => self
+                .extra_headers
+                .push((name.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn remove_extra_header(&mut self, name: &str) -> Option<String> {
+        let pos = self
+            .extra_headers
+            .iter()
+            .position(|(n, _)| n.eq_ignore_ascii_case(name))?;
+        Some(self.extra_headers.remove(pos).1)
+    }
+
+    pub fn resolve_urls(&self, base: &str) -> Result<Vec<String>, ZsyncError> {
+        let base = Url::parse(base).map_err(|_| ZsyncError::InvalidUrl(base.to_string()))?;
+        self.urls
+            .iter()
+            .map(|url| {
+                base.join(url)
+                    .map(String::from)
+                    .map_err(|_| ZsyncError::InvalidUrl(url.clone()))
+            })
+            .collect()
+    }
+
+    pub fn write_zsync(&self, mut w: impl Write) -> Result<(), ZsyncError> {
+        writeln!(w, "zsync: {}", self.zsync)?;
+        writeln!(w, "Filename: {}", self.filename)?;
+        if !self.z_filename.is_empty() {
+            writeln!(w, "Z-Filename: {}", self.z_filename)?;
+        }
+        writeln!(w, "MTime: {}", self.m_time.to_rfc2822())?;
+        writeln!(w, "Blocksize: {}", self.blocksize)?;
+        writeln!(w, "Length: {}", self.length)?;
+        writeln!(
+            w,
+            "Hash-Lengths: {},{},{}",
+            self.seq_num, self.rsum_bytes, self.checksum_bytes
+        )?;
+        for url in &self.urls {
+            writeln!(w, "URL: {}", url)?;
+        }
+        for url in &self.z_urls {
+            writeln!(w, "Z-URL: {}", url)?;
+        }
+        if !self.sha1.is_empty() {
+            writeln!(w, "SHA-1: {}", self.sha1)?;
+        }
+        if !self.md5.is_empty() {
+            writeln!(w, "MD5: {}", self.md5)?;
+        }
+        if !self.sha256.is_empty() {
+            writeln!(w, "SHA-256: {}", self.sha256)?;
+        }
+        if !self.recompress.is_empty() {
+            writeln!(w, "Recompress: {}", self.recompress)?;
+        }
+        if !self.z_map.is_empty() {
+            writeln!(w, "Z-Map2: {}", self.z_map.len())?;
+            w.write_all(&gzip::encode_z_map(&self.z_map)?)?;
+        }
+        for (name, value) in &self.extra_headers {
+            writeln!(w, "{}: {}", name, value)?;
+        }
+        writeln!(w)?;
+
+        for pair in self.sorted_pairs() {
+            let weak = pack_weak_sum(pair.weak);
+            w.write_all(&weak[..self.rsum_bytes as usize])?;
+            w.write_all(&pair.strong)?;
+        }
+
+        w.flush()?;
+        Ok(())
+    }
 
-        //let mut kvps = HashMap::new();
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ZsyncError> {
+        let mut buf = Vec::new();
+        self.write_zsync(&mut buf)?;
+        Ok(buf)
+    }
 
-        let mut run: bool = true;
+    pub fn save_zsync(&self, path: &Path) -> Result<(), ZsyncError> {
+        let file = File::create(path)?;
+        self.write_zsync(BufWriter::new(file))
+    }
 
-        while run {
+    fn parse_reader(&mut self, mut br: impl BufRead) -> Result<(), ZsyncError> {
+        loop {
             let mut line = String::new();
-            match br.read_line(&mut line) {
-                Ok(_) => {
-                    if !line.is_empty() {
-                        let splitted: Vec<String> =
-                            line.splitn(2, ':').map(|s| s.trim().to_string()).collect();
-
-                        if splitted.len() == 2 {
-                            let value = splitted[1].clone();
-                            // dbg!(value.clone());
-                            match splitted[0].trim().to_lowercase().as_ref() {
-                                "zsync" => self.zsync = value,
-                                "filename" => self.filename = value,
-                                "mtime" => {
-                                    self.m_time =
-                                        DateTime::parse_from_rfc2822(&value).expect("no parse")
-                                }
-                                "blocksize" => self.blocksize = value.parse().unwrap_or_default(),
-                                "length" => self.length = value.parse().unwrap_or_default(),
-                                "hash-lengths" => {
-                                    let hash_lenghts: Vec<u32> = value
-                                        .split(',')
-                                        .map(|s| s.parse().unwrap_or_default())
-                                        .collect();
-                                    self.seq_num = *hash_lenghts.get(0).unwrap_or(&0);
-                                    self.rsum_bytes = *hash_lenghts.get(1).unwrap_or(&0);
-                                    self.checksum_bytes = *hash_lenghts.get(2).unwrap_or(&0);
-                                }
-                                "url" => self.url = value,
-                                "sha-1" => self.sha1 = value,
-                                e => println!("Unknown: {}", e),
-                            }
-                        } else if line.len() == 1 && line.bytes().next() == Some(0x0A) {
-                            run = false;
-                        }
-                    } else {
-                        run = false;
+            if br.read_line(&mut line)? == 0 || line == "\n" {
+                break;
+            }
+
+            let splitted: Vec<String> = line.splitn(2, ':').map(|s| s.trim().to_string()).collect();
+            if splitted.len() != 2 {
+                return Err(ZsyncError::MalformedHeader(line.trim_end().to_string()));
+            }
+
+            let value = splitted[1].clone();
+            match splitted[0].to_lowercase().as_ref() {
+                "zsync" => self.zsync = value,
+                "filename" => self.filename = value,
+                "mtime" => {
+                    self.m_time = DateTime::parse_from_rfc2822(&value)
+                        .map_err(|_| bad_header(&splitted[0], &value))?
+                }
+                "blocksize" => {
+                    self.blocksize = value
+                        .parse()
+                        .map_err(|_| bad_header(&splitted[0], &value))?
+                }
+                "length" => {
+                    self.length = value
+                        .parse()
+                        .map_err(|_| bad_header(&splitted[0], &value))?
+                }
+                "hash-lengths" => {
+                    let hash_lengths = value
+                        .split(',')
+                        .map(|s| s.trim().parse::<u32>())
+                        .collect::<Result<Vec<u32>, _>>()
+                        .map_err(|_| ZsyncError::InvalidHashLengths(value.clone()))?;
+                    if hash_lengths.len() != 3 {
+                        return Err(ZsyncError::InvalidHashLengths(value));
                     }
+                    self.seq_num = hash_lengths[0];
+                    self.rsum_bytes = hash_lengths[1];
+                    self.checksum_bytes = hash_lengths[2];
                 }
-                Err(_) => run = false,
+                "url" => self.urls.push(value),
+                "sha-1" => self.sha1 = value,
+                "md5" => self.md5 = value,
+                "sha-256" => self.sha256 = value,
+                "z-filename" => self.z_filename = value,
+                "z-url" => self.z_urls.push(value),
+                "recompress" => self.recompress = value,
+                "z-map2" => {
+                    let count: usize = value
+                        .parse()
+                        .map_err(|_| bad_header(&splitted[0], &value))?;
+                    let len = count
+                        .checked_mul(4)
+                        .ok_or_else(|| bad_header(&splitted[0], &value))?;
+                    // The count is untrusted, so only allocate what is actually there.
+                    let mut raw = Vec::new();
+                    (&mut br).take(len as u64).read_to_end(&mut raw)?;
+                    if raw.len() != len {
+                        return Err(bad_header(&splitted[0], &value));
+                    }
+                    self.z_map = gzip::decode_z_map(&raw);
+                }
+                _ => self.extra_headers.push((splitted[0].clone(), value)),
             }
         }
 
-        self.block_num = (self.length as f64 / self.blocksize as f64).ceil() as u32;
-
-        //br.seek(SeekFrom::Current(-4));
         let mut buf = Vec::new();
-        //br.seek(SeekFrom::Start(0))?;
         br.read_to_end(&mut buf)?;
 
+        self.load_checksums(buf)
+    }
+
+    pub(crate) fn load_checksums(&mut self, buf: Vec<u8>) -> Result<(), ZsyncError> {
+        self.validate()?;
+        self.block_num = (self.length as f64 / self.blocksize as f64).ceil() as u32;
+
+        let expected = self.block_num as usize * (self.rsum_bytes + self.checksum_bytes) as usize;
+        if buf.len() < expected {
+            return Err(ZsyncError::TruncatedChecksums {
+                expected,
+                actual: buf.len(),
+            });
+        } else if buf.len() > expected {
+            return Err(ZsyncError::TrailingChecksumData {
+                expected,
+                actual: buf.len(),
+            });
+        }
+
         self.fill_hash_table(buf);
+        Ok(())
+    }
 
+    /// Checks the header fields the checksum table depends on.
+    pub fn validate(&self) -> Result<(), ZsyncError> {
+        if !is_valid_blocksize(self.blocksize) {
+            return Err(ZsyncError::InvalidBlocksize(self.blocksize));
+        }
+        if !(1..=2).contains(&self.seq_num)
+            || !(1..=4).contains(&self.rsum_bytes)
+            || !(3..=16).contains(&self.checksum_bytes)
+        {
+            return Err(ZsyncError::InvalidHashLengths(format!(
+                "{},{},{}",
+                self.seq_num, self.rsum_bytes, self.checksum_bytes
+            )));
+        }
         Ok(())
     }
 
@@ -115,14 +417,13 @@ impl MetaFile {
             i -= 1;
         }
 
-        self.hashtable = ChainingHash::new((2 << (i - 1)) as i32);
+        self.hashtable = ChainingHash::new(2 << (i - 1));
 
         let mut offset: i64 = 0;
-        let mut weak_sum: i32 = 0;
         let mut seq: i32 = 0;
         let mut off = 0;
 
-        let mut weak = vec![0u8; 4];
+        let mut weak = [0u8; 4];
         let mut strong_sum = vec![0u8; self.checksum_bytes as usize];
 
         while seq < self.block_num as i32 {
@@ -131,11 +432,11 @@ impl MetaFile {
                 off += 1;
             }
 
-            for s in 0..strong_sum.len() {
-                strong_sum[s] = checksums[off];
-                off += 1;
-            }
-            weak_sum = 0;
+            let strong_len = strong_sum.len();
+            strong_sum.copy_from_slice(&checksums[off..off + strong_len]);
+            off += strong_len;
+
+            let mut weak_sum: i32 = 0;
             weak_sum += (weak[2] as i32 & 0x000000FF) << 24;
             weak_sum += (weak[3] as i32 & 0x000000FF) << 16;
             weak_sum += (weak[0] as i32 & 0x000000FF) << 8;
@@ -156,6 +457,23 @@ impl MetaFile {
     }
 }
 
+// Inverse of the byte shuffle in `fill_hash_table`.
+pub(crate) fn pack_weak_sum(weak_sum: i32) -> [u8; 4] {
+    [
+        (weak_sum >> 8) as u8,
+        weak_sum as u8,
+        (weak_sum >> 24) as u8,
+        (weak_sum >> 16) as u8,
+    ]
+}
+
+fn bad_header(name: &str, value: &str) -> ZsyncError {
+    ZsyncError::BadHeader {
+        name: name.to_string(),
+        value: value.to_string(),
+    }
+}
+
 impl Default for MetaFile {
     fn default() -> Self {
         Self::new()
@@ -174,9 +492,119 @@ impl Clone for MetaFile {
             rsum_bytes: self.rsum_bytes,
             checksum_bytes: self.checksum_bytes,
             block_num: self.block_num,
-            url: self.url.clone(),
+            urls: self.urls.clone(),
             sha1: self.sha1.clone(),
+            md5: self.md5.clone(),
+            sha256: self.sha256.clone(),
+            z_filename: self.z_filename.clone(),
+            z_urls: self.z_urls.clone(),
+            recompress: self.recompress.clone(),
+            z_map: self.z_map.clone(),
+            extra_headers: self.extra_headers.clone(),
             hashtable: self.hashtable.clone(),
         }
     }
 }
+
+// Serialized form of `MetaFile`: the headers plus the block checksums in
+// file order, rather than the layout of the internal hash table.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MetaFileRepr {
+    zsync: String,
+    filename: String,
+    m_time: DateTime<FixedOffset>,
+    blocksize: usize,
+    length: usize,
+    seq_num: u32,
+    rsum_bytes: u32,
+    checksum_bytes: u32,
+    urls: Vec<String>,
+    sha1: String,
+    #[serde(default)]
+    md5: String,
+    #[serde(default)]
+    sha256: String,
+    #[serde(default)]
+    z_filename: String,
+    #[serde(default)]
+    z_urls: Vec<String>,
+    #[serde(default)]
+    recompress: String,
+    #[serde(default)]
+    z_map: Vec<ZMapEntry>,
+    extra_headers: Vec<(String, String)>,
+    blocks: Vec<BlockChecksum>,
+}
+
+#[cfg(feature = "serde")]
+impl From<MetaFile> for MetaFileRepr {
+    fn from(mf: MetaFile) -> Self {
+        MetaFileRepr {
+            blocks: mf.blocks().collect(),
+            zsync: mf.zsync,
+            filename: mf.filename,
+            m_time: mf.m_time,
+            blocksize: mf.blocksize,
+            length: mf.length,
+            seq_num: mf.seq_num,
+            rsum_bytes: mf.rsum_bytes,
+            checksum_bytes: mf.checksum_bytes,
+            urls: mf.urls,
+            sha1: mf.sha1,
+            md5: mf.md5,
+            sha256: mf.sha256,
+            z_filename: mf.z_filename,
+            z_urls: mf.z_urls,
+            recompress: mf.recompress,
+            z_map: mf.z_map,
+            extra_headers: mf.extra_headers,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<MetaFileRepr> for MetaFile {
+    type Error = ZsyncError;
+
+    fn try_from(repr: MetaFileRepr) -> Result<Self, Self::Error> {
+        let mut mf = MetaFile {
+            zsync: repr.zsync,
+            filename: repr.filename,
+            m_time: repr.m_time,
+            blocksize: repr.blocksize,
+            length: repr.length,
+            seq_num: repr.seq_num,
+            rsum_bytes: repr.rsum_bytes,
+            checksum_bytes: repr.checksum_bytes,
+            urls: repr.urls,
+            sha1: repr.sha1,
+            md5: repr.md5,
+            sha256: repr.sha256,
+            z_filename: repr.z_filename,
+            z_urls: repr.z_urls,
+            recompress: repr.recompress,
+            z_map: repr.z_map,
+            extra_headers: repr.extra_headers,
+            ..MetaFile::new()
+        };
+        mf.validate()?;
+
+        let mut checksums = Vec::new();
+        for block in &repr.blocks {
+            if block.strong.len() != mf.checksum_bytes as usize {
+                return Err(ZsyncError::InvalidHashLengths(format!(
+                    "{},{},{}",
+                    mf.seq_num,
+                    mf.rsum_bytes,
+                    block.strong.len()
+                )));
+            }
+            checksums.extend_from_slice(&block.weak.to_be_bytes()[4 - mf.rsum_bytes as usize..]);
+            checksums.extend_from_slice(&block.strong);
+        }
+
+        mf.load_checksums(checksums)?;
+        Ok(mf)
+    }
+}
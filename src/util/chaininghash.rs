@@ -4,7 +4,6 @@ use super::checksumpair::ChecksumPair;
 pub struct ChainingHash {
     hash_array: Vec<Vec<ChecksumPair>>,
     array_size: i32,
-    index: i32,
 }
 
 impl ChainingHash {
@@ -17,7 +16,6 @@ impl ChainingHash {
         ChainingHash {
             array_size: size,
             hash_array: array,
-            index: 0,
         }
     }
 
@@ -34,44 +32,27 @@ impl ChainingHash {
         let hash_value = self.hash_function(p_key);
 
         let array = &mut self.hash_array[hash_value as usize];
-        if let Some(pos) = array.iter().position(|pk| pk == p_key) {
+        if let Some(pos) = array
+            .iter()
+            .position(|pk| pk == p_key && pk.seq == p_key.seq)
+        {
             array.remove(pos);
         }
     }
 
-    pub fn find(&mut self, p_key: &ChecksumPair) -> Option<ChecksumPair> {
-        let hash_value = self.hash_function(p_key);
-
-        let array = &self.hash_array[hash_value as usize];
-
-        for i in 0..array.len() {
-            let pair = &array[i];
-            if pair.weak == p_key.weak {
-                self.index = i as i32;
-                return Some(pair.clone());
-            }
-        }
-        None
+    pub fn iter(&self) -> impl Iterator<Item = &ChecksumPair> {
+        self.hash_array.iter().flatten()
     }
 
-    pub fn find_match(&mut self, p_key: &ChecksumPair) -> Option<ChecksumPair> {
-        let hash_value = self.hash_function(p_key);
-
-        let array = &self.hash_array[hash_value as usize];
-        let pair = &array[self.index as usize];
-
-        if pair.weak == p_key.weak && pair.strong == p_key.strong {
-            return Some(pair.clone());
-        }
-
-        for i in 0..array.len() {
-            let pair = &array[i as usize];
-            if pair.weak == p_key.weak && pair.strong == p_key.strong {
-                return Some(pair.clone());
-            }
-        }
-
-        None
+    /// Pairs whose weak checksum equals `weak`.
+    pub fn find_all(&self, weak: i32) -> impl Iterator<Item = &ChecksumPair> {
+        let key = ChecksumPair {
+            weak,
+            ..ChecksumPair::new()
+        };
+        self.hash_array[self.hash_function(&key) as usize]
+            .iter()
+            .filter(move |pair| pair.weak == weak)
     }
 }
 
@@ -80,7 +61,6 @@ impl Clone for ChainingHash {
         Self {
             hash_array: self.hash_array.clone(),
             array_size: self.array_size,
-            index: self.index,
         }
     }
 }
@@ -31,23 +31,10 @@ impl ChecksumPair {
     }
 
     pub fn hash_code(&self) -> i32 {
-        let weak_byte: Vec<u8> = vec![
-            (self.weak >> 24) as u8,
-            ((self.weak << 8) >> 24) as u8,
-            ((self.weak << 16) >> 24) as u8,
-            ((self.weak << 24) >> 24) as u8,
-        ];
-
-        let weak_add: Vec<u8> = vec![
-            weak_byte[0].overflowing_add(weak_byte[1]).0,
-            weak_byte[2].overflowing_add(weak_byte[3]).0,
-        ];
-        let mut hash_code: i32 = 0;
-        for i in 0..2 {
-            let shift: i32 = (1 - i) * 8;
-            hash_code += ((weak_add[i as usize] as i32) & 0x00FF) << shift;
-        }
-        hash_code
+        let weak_byte = self.weak.to_be_bytes();
+        let high = weak_byte[0].wrapping_add(weak_byte[1]) as i32;
+        let low = weak_byte[2].wrapping_add(weak_byte[3]) as i32;
+        (high << 8) | low
     }
 }
 
@@ -11,14 +11,14 @@ impl Generator {
         Generator { config }
     }
 
-    pub fn generate_weak_sum(&mut self, buf: &mut [u8], offset: i32) -> i32 {
+    pub fn generate_weak_sum(&mut self, buf: &[u8], offset: i32) -> i32 {
         self.config
             .weak_sum
-            .first(buf, offset, self.config.block_length);
+            .first(buf, offset as usize, self.config.block_length as usize);
         self.config.weak_sum.get_value()
     }
 
-    pub fn generate_strong_sum(&mut self, buf: &mut [u8], off: usize, len: usize) -> Vec<u8> {
+    pub fn generate_strong_sum(&mut self, buf: &[u8], off: usize, len: usize) -> Vec<u8> {
         self.config.strong_sum.update(&buf[off..(off + len)]);
         let hasher = self.config.strong_sum.clone();
         let hash: Vec<u8> = hasher.finalize().to_vec();
@@ -34,9 +34,4 @@ impl Generator {
         );
         strong_sum
     }
-
-    pub fn generate_roll_sum(&mut self, b: u8) -> i32 {
-        self.config.weak_sum.roll(b);
-        self.config.weak_sum.get_value()
-    }
 }
@@ -3,4 +3,5 @@ pub(crate) mod checksumpair;
 pub(crate) mod configuration;
 pub(crate) mod copy;
 pub(crate) mod generator;
+pub(crate) mod read;
 pub(crate) mod rsum;
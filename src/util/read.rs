@@ -0,0 +1,17 @@
+use std::io::{ErrorKind, Read};
+
+use crate::error::ZsyncError;
+
+// Fills `buf` from `reader`, returning fewer bytes only at end of input.
+pub(crate) fn read_block(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize, ZsyncError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
@@ -45,34 +45,20 @@ impl Rsum {
         }
     }
 
-    pub fn check(&mut self, buf: &mut [u8], offset: i32, length: i32) {
+    pub fn first(&mut self, buf: &[u8], offset: usize, length: usize) {
         self.reset();
-        let mut index = offset;
-        let mut unsigned_b: i16;
-        for i in (1..(length + 1)).rev() {
-            unsigned_b = buf[index as usize] as i16;
+        let block = &buf[offset..offset + length];
+        for (i, byte) in (1..(length as i32 + 1)).rev().zip(block) {
+            let unsigned_b = *byte as i16;
             self.a = self.a.overflowing_add(unsigned_b).0;
             self.b = self.b.overflowing_add((i * unsigned_b as i32) as i16).0;
-            index += 1;
-        }
-    }
-
-    pub fn first(&mut self, buf: &mut [u8], offset: i32, length: i32) {
-        self.reset();
-        let mut index = offset;
-        let mut unsigned_b: i16;
-        for i in (1..(length + 1)).rev() {
-            unsigned_b = buf[index as usize] as i16;
-            self.a = self.a.overflowing_add(unsigned_b).0;
-            self.b = self.b.overflowing_add((i * unsigned_b as i32) as i16).0;
-            index += 1;
         }
 
-        self.block_length = length;
+        self.block_length = length as i32;
         //self.buffer = vec![0; self.block_length];
         self.buffer = Vec::new();
-        self.buffer.resize(self.block_length as usize, 0);
-        arr_copy(&buf.to_vec(), 0, &mut self.buffer, 0, length as usize);
+        self.buffer.resize(length, 0);
+        arr_copy(buf, offset, &mut self.buffer, 0, length);
         //self.buffer.resize(self.block_length as usize, 0);
     }
 
@@ -83,3 +69,33 @@ impl Rsum {
     //     b as i16
     // }
 }
+
+/// Reduces a rolling sum to the `rsum_bytes` the control file stores, in the
+/// same byte layout `MetaFile` uses for its hash table.
+pub(crate) fn truncate_weak_sum(weak: i32, rsum_bytes: u32) -> i32 {
+    let rsum: [u8; 4] = match rsum_bytes {
+        1 => [0, 0, ((weak << 8) >> 24) as u8, 0],
+        2 => [0, 0, (weak >> 24) as u8, ((weak << 8) >> 24) as u8],
+        3 => [
+            ((weak << 8) >> 24) as u8,
+            0,
+            ((weak << 24) >> 24) as u8,
+            (weak >> 24) as u8,
+        ],
+        4 => [
+            (weak >> 24) as u8,
+            ((weak << 8) >> 24) as u8,
+            ((weak << 16) >> 24) as u8,
+            ((weak << 24) >> 24) as u8,
+        ],
+        _ => [0; 4],
+    };
+
+    let mut weak_sum: i32 = 0;
+    weak_sum += (rsum[0] as i32 & 0x000000FF) << 24;
+    weak_sum += (rsum[1] as i32 & 0x000000FF) << 16;
+    weak_sum += (rsum[2] as i32 & 0x000000FF) << 8;
+    weak_sum += rsum[3] as i32 & 0x000000FF;
+
+    weak_sum
+}
@@ -0,0 +1,691 @@
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use chrono::{DateTime, Timelike, Utc};
+use sha1::{Digest, Sha1};
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::cancel::CancellationToken;
+use crate::error::ZsyncError;
+use crate::file_checksum::to_hex;
+use crate::gzip::{self, BitReader, Inflater};
+use crate::meta_file::{is_valid_blocksize, pack_weak_sum, MetaFile};
+use crate::util::configuration::Configuration;
+use crate::util::generator::Generator;
+use crate::util::read::read_block;
+use crate::util::rsum::truncate_weak_sum;
+
+/// Value written to the `zsync:` header, the oldest client version able to read the output.
+pub const ZSYNC_VERSION: &str = "0.6.2";
+
+const MAX_CHECKSUM_BYTES: u32 = 16;
+
+// Full-width rsum and MD4 of each block, in file order.
+type BlockSums = Vec<(i32, Vec<u8>)>;
+
+/// Outcome of [`ZsyncMaker::make_dir`], listing the source files visited.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BatchSummary {
+    /// Files whose control file was written.
+    pub generated: Vec<PathBuf>,
+    /// Files whose control file already matched their size and mtime.
+    pub skipped: Vec<PathBuf>,
+    /// Files and directories that could not be processed, with the error.
+    /// Not serialized, as errors cannot be.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub failed: Vec<(PathBuf, ZsyncError)>,
+}
+
+/// Builds control files from local files or streams, like `zsyncmake`.
+///
+/// Every field left as `None` is derived from the input length with the same
+/// heuristics `zsyncmake` uses, see [`auto_blocksize`] and [`auto_hash_lengths`].
+pub struct ZsyncMaker {
+    pub blocksize: Option<usize>,
+    pub seq_matches: Option<u32>,
+    pub rsum_bytes: Option<u32>,
+    pub checksum_bytes: Option<u32>,
+    /// Defaults to the file name of the input path.
+    pub filename: Option<String>,
+    pub urls: Vec<String>,
+    /// Expected length of streamed input, used to pick the blocksize before
+    /// the real length is known.
+    pub size_hint: Option<u64>,
+    /// `gzip` options written to `Recompress` by [`ZsyncMaker::make_gzip`].
+    /// Guessed from the gzip header when unset.
+    pub recompress: Option<String>,
+    /// Checked while hashing; once cancelled, generation stops with
+    /// [`ZsyncError::Cancelled`].
+    pub cancel: Option<CancellationToken>,
+}
+
+impl ZsyncMaker {
+    pub fn new() -> Self {
+        ZsyncMaker {
+            blocksize: None,
+            seq_matches: None,
+            rsum_bytes: None,
+            checksum_bytes: None,
+            filename: None,
+            urls: Vec::new(),
+            size_hint: None,
+            recompress: None,
+            cancel: None,
+        }
+    }
+
+    pub fn make(&self, path: &Path) -> Result<MetaFile, ZsyncError> {
+        let (file, mut mf) = self.open(path)?;
+        self.hash_stream(BufReader::new(file), &mut mf)?;
+        Ok(mf)
+    }
+
+    /// Like [`ZsyncMaker::make`], but hashes block-aligned chunks of the file
+    /// on `threads` threads while the calling thread computes the SHA-1.
+    ///
+    /// The result is identical to the serial path.
+    pub fn make_parallel(&self, path: &Path, threads: usize) -> Result<MetaFile, ZsyncError> {
+        let (file, mut mf) = self.open(path)?;
+        let blocksize = mf.blocksize;
+        if !is_valid_blocksize(blocksize) {
+            return Err(ZsyncError::InvalidBlocksize(blocksize));
+        }
+
+        let length = file.metadata()?.len();
+        let block_count = length.div_ceil(blocksize as u64);
+        let chunk_blocks = block_count.div_ceil(threads.max(1) as u64).max(1);
+        let chunk_len = chunk_blocks * blocksize as u64;
+        // Rounding the chunks up can leave later threads nothing to hash.
+        let threads = block_count.div_ceil(chunk_blocks).max(1);
+
+        let (chunks, sha1) = thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|i| {
+                    scope.spawn(move || -> Result<_, ZsyncError> {
+                        let mut chunk = File::open(path)?;
+                        chunk.seek(SeekFrom::Start(i * chunk_len))?;
+                        hash_blocks(
+                            BufReader::new(chunk).take(chunk_len),
+                            blocksize,
+                            None,
+                            None,
+                            self.cancel.as_ref(),
+                        )
+                    })
+                })
+                .collect();
+
+            let mut sha1 = Sha1::new();
+            let sha1_len = hash_sha1(BufReader::new(file), &mut sha1, self.cancel.as_ref());
+
+            let chunks: Vec<_> = handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .collect();
+            (chunks, sha1_len.map(|len| (len, sha1)))
+        });
+
+        let (sha1_len, sha1) = sha1?;
+        let mut sums = Vec::with_capacity(block_count as usize);
+        let mut hashed_len = 0;
+        for chunk in chunks {
+            let (chunk_sums, len) = chunk?;
+            // Only the last chunk may end short of its block-aligned range.
+            if hashed_len % chunk_len != 0 {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+            hashed_len += len as u64;
+            sums.extend(chunk_sums);
+        }
+        if hashed_len != sha1_len as u64 {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+
+        self.finish(&mut mf, sha1_len, sums, sha1)?;
+        Ok(mf)
+    }
+
+    /// Like [`ZsyncMaker::make`], but also returns the [`BlockDigests`] that
+    /// [`ZsyncMaker::make_incremental`] needs to regenerate it after an edit.
+    pub fn make_with_digests(&self, path: &Path) -> Result<(MetaFile, BlockDigests), ZsyncError> {
+        self.make_digested(path, Vec::new())
+    }
+
+    /// Regenerates the control file of `path` after a local edit, reusing the
+    /// strong checksums of `previous` for blocks whose XXH3-128 in `digests`
+    /// is unchanged at the same offset, so only changed blocks go through MD4.
+    ///
+    /// Reuse needs `digests` to belong to `previous`, and the same blocksize
+    /// and `Hash-Lengths`, otherwise every block is rehashed. The whole file
+    /// is still read for the weak checksums and the SHA-1. Also returns the
+    /// digests of the new file, for the next edit.
+    pub fn make_incremental(
+        &self,
+        previous: &MetaFile,
+        digests: &BlockDigests,
+        path: &Path,
+    ) -> Result<(MetaFile, BlockDigests), ZsyncError> {
+        let length = fs::metadata(path)?.len();
+        let blocksize = self.blocksize.unwrap_or_else(|| auto_blocksize(length));
+        let (seq_matches, rsum_bytes, checksum_bytes) = auto_hash_lengths(length, blocksize);
+        let reusable = previous.blocksize == blocksize
+            && previous.seq_num == self.seq_matches.unwrap_or(seq_matches)
+            && previous.rsum_bytes == self.rsum_bytes.unwrap_or(rsum_bytes)
+            && previous.checksum_bytes == self.checksum_bytes.unwrap_or(checksum_bytes)
+            && digests.blocksize == blocksize
+            && digests.sha1.eq_ignore_ascii_case(&previous.sha1)
+            && digests.digests.len() == previous.block_num as usize;
+
+        let previous = if reusable {
+            digests
+                .digests
+                .iter()
+                .copied()
+                .zip(previous.blocks().map(|block| block.strong))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        self.make_digested(path, previous)
+    }
+
+    /// Generates a control file for the uncompressed content of a gzip file.
+    ///
+    /// `Filename` defaults to the input name without its `.gz` suffix, the
+    /// input itself becomes `Z-Filename` and `urls` are written as `Z-URL`s,
+    /// since clients fetch compressed ranges through the `Z-Map2`.
+    pub fn make_gzip(&self, path: &Path) -> Result<MetaFile, ZsyncError> {
+        let (file, mut mf) = self.open(path)?;
+        mf.z_filename = std::mem::take(&mut mf.filename);
+        mf.filename = match &self.filename {
+            Some(name) => name.clone(),
+            None => mf
+                .z_filename
+                .strip_suffix(".gz")
+                .unwrap_or(&mf.z_filename)
+                .to_string(),
+        };
+        mf.z_urls = std::mem::take(&mut mf.urls);
+
+        let mut reader = BufReader::new(file);
+        let header = gzip::read_gzip_header(&mut reader)?;
+        let bits = BitReader::new(reader, header.len() as u64 * 8);
+        let mut inflater = Inflater::new(bits, 0, Some(Vec::new()));
+        self.hash_stream(&mut inflater, &mut mf)?;
+
+        let crc = inflater.crc();
+        let (z_map, mut bits) = inflater.finish();
+        gzip::check_gzip_trailer(&mut bits, crc, mf.length as u64)?;
+        mf.z_map = z_map;
+
+        let options = match &self.recompress {
+            Some(options) => options.clone(),
+            None => guess_gzip_options(&header),
+        };
+        mf.recompress = format!("{} {}", to_hex(&header), options)
+            .trim_end()
+            .to_string();
+
+        Ok(mf)
+    }
+
+    /// Writes a `.zsync` next to every file below `dir`.
+    ///
+    /// Files whose existing control file records the same `Length` and
+    /// `MTime` are skipped. Each control file gets the file's own name as
+    /// `Filename`, so `filename` is ignored here. Its `URL`s are the file's
+    /// path below `dir` with each of `urls` as a prefix, or just the file's
+    /// name, relative to the control file, if `urls` is empty. Symlinks are
+    /// not followed.
+    ///
+    /// A file or subdirectory that fails is listed in
+    /// [`BatchSummary::failed`] and the walk goes on; only failing to read
+    /// `dir` itself, or being cancelled, stops it.
+    pub fn make_dir(&self, dir: &Path) -> Result<BatchSummary, ZsyncError> {
+        let mut summary = BatchSummary::default();
+        self.make_dir_into(dir, Path::new(""), &mut summary)?;
+        Ok(summary)
+    }
+
+    // `relative` is the path of `dir` below the directory `make_dir` was
+    // called with.
+    fn make_dir_into(
+        &self,
+        dir: &Path,
+        relative: &Path,
+        summary: &mut BatchSummary,
+    ) -> Result<(), ZsyncError> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let relative = relative.join(entry.file_name());
+            let result = match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {
+                    self.make_dir_into(&path, &relative, summary)
+                }
+                Ok(file_type)
+                    if file_type.is_file() && path.extension().is_none_or(|ext| ext != "zsync") =>
+                {
+                    self.make_dir_entry(&path, &relative, summary)
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+
+            match result {
+                Ok(()) => {}
+                Err(ZsyncError::Cancelled) => return Err(ZsyncError::Cancelled),
+                Err(e) => summary.failed.push((path, e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn make_dir_entry(
+        &self,
+        path: &Path,
+        relative: &Path,
+        summary: &mut BatchSummary,
+    ) -> Result<(), ZsyncError> {
+        let mut control = path.as_os_str().to_owned();
+        control.push(".zsync");
+        let control = PathBuf::from(control);
+
+        if is_up_to_date(path, &control)? {
+            summary.skipped.push(path.to_path_buf());
+            return Ok(());
+        }
+
+        let name = relative
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let url_path = relative
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let mut mf = self.make(path)?;
+        mf.urls = if self.urls.is_empty() {
+            vec![name.clone()]
+        } else {
+            self.urls
+                .iter()
+                .map(|prefix| format!("{}{}", prefix, url_path))
+                .collect()
+        };
+        mf.filename = name;
+        mf.save_zsync(&control)?;
+        summary.generated.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Generates a control file in a single pass over `reader`.
+    ///
+    /// Only the block checksums are kept in memory. `Length` and
+    /// `Hash-Lengths` are filled in once the end of the input is reached, and
+    /// `MTime` is set to the current time.
+    pub fn make_from_reader(&self, reader: impl Read) -> Result<MetaFile, ZsyncError> {
+        let mut mf = self.new_meta_file(self.size_hint.unwrap_or(0));
+        let now = Utc::now();
+        mf.m_time = now.with_nanosecond(0).unwrap_or(now).into();
+
+        self.hash_stream(reader, &mut mf)?;
+        Ok(mf)
+    }
+
+    fn new_meta_file(&self, expected_length: u64) -> MetaFile {
+        let mut mf = MetaFile::new();
+        mf.zsync = String::from(ZSYNC_VERSION);
+        mf.filename = self.filename.clone().unwrap_or_default();
+        mf.blocksize = self
+            .blocksize
+            .unwrap_or_else(|| auto_blocksize(expected_length));
+        mf.urls = self.urls.clone();
+        mf
+    }
+
+    fn open(&self, path: &Path) -> Result<(File, MetaFile), ZsyncError> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let modified: DateTime<Utc> = metadata.modified()?.into();
+
+        let mut mf = self.new_meta_file(metadata.len());
+        if self.filename.is_none() {
+            mf.filename = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+        }
+        mf.m_time = modified.with_nanosecond(0).unwrap_or(modified).into();
+
+        Ok((file, mf))
+    }
+
+    fn make_digested(
+        &self,
+        path: &Path,
+        previous: Vec<([u8; 16], Vec<u8>)>,
+    ) -> Result<(MetaFile, BlockDigests), ZsyncError> {
+        let (file, mut mf) = self.open(path)?;
+        if !is_valid_blocksize(mf.blocksize) {
+            return Err(ZsyncError::InvalidBlocksize(mf.blocksize));
+        }
+
+        let mut digesting = Digesting {
+            previous,
+            digests: Vec::new(),
+        };
+        let mut sha1 = Sha1::new();
+        let (sums, length) = hash_blocks(
+            BufReader::new(file),
+            mf.blocksize,
+            Some(&mut sha1),
+            Some(&mut digesting),
+            self.cancel.as_ref(),
+        )?;
+        self.finish(&mut mf, length, sums, sha1)?;
+
+        let digests = BlockDigests {
+            sha1: mf.sha1.clone(),
+            blocksize: mf.blocksize,
+            digests: digesting.digests,
+        };
+        Ok((mf, digests))
+    }
+
+    fn hash_stream(&self, reader: impl Read, mf: &mut MetaFile) -> Result<(), ZsyncError> {
+        if !is_valid_blocksize(mf.blocksize) {
+            return Err(ZsyncError::InvalidBlocksize(mf.blocksize));
+        }
+
+        let mut sha1 = Sha1::new();
+        let (sums, length) = hash_blocks(
+            reader,
+            mf.blocksize,
+            Some(&mut sha1),
+            None,
+            self.cancel.as_ref(),
+        )?;
+        self.finish(mf, length, sums, sha1)
+    }
+
+    // Blocks are hashed at full width, since the truncation depends on the
+    // total length; this fills in the length dependent headers and packs them.
+    fn finish(
+        &self,
+        mf: &mut MetaFile,
+        length: usize,
+        sums: BlockSums,
+        sha1: Sha1,
+    ) -> Result<(), ZsyncError> {
+        let (seq_matches, rsum_bytes, checksum_bytes) =
+            auto_hash_lengths(length as u64, mf.blocksize);
+        mf.length = length;
+        mf.seq_num = self.seq_matches.unwrap_or(seq_matches);
+        mf.rsum_bytes = self.rsum_bytes.unwrap_or(rsum_bytes);
+        mf.checksum_bytes = self.checksum_bytes.unwrap_or(checksum_bytes);
+        mf.sha1 = to_hex(&sha1.finalize());
+        mf.validate()?;
+
+        let mut checksums =
+            Vec::with_capacity(sums.len() * (mf.rsum_bytes + mf.checksum_bytes) as usize);
+        for (weak, strong) in sums {
+            let weak = truncate_weak_sum(weak, mf.rsum_bytes);
+            checksums.extend_from_slice(&pack_weak_sum(weak)[..mf.rsum_bytes as usize]);
+            checksums.extend_from_slice(&strong[..mf.checksum_bytes as usize]);
+        }
+
+        mf.load_checksums(checksums)
+    }
+}
+
+// The XFL byte records whether gzip compressed with --best or --fast, and
+// `gzip -n` leaves out the name and timestamp.
+fn guess_gzip_options(header: &[u8]) -> String {
+    let mut options = Vec::new();
+    match header[8] {
+        2 => options.push("--best"),
+        4 => options.push("--fast"),
+        _ => {}
+    }
+    if header[3] & 0x08 == 0 && header[4..8] == [0; 4] {
+        options.push("-n");
+    }
+    options.join(" ")
+}
+
+// An unreadable control file counts as stale rather than as an error.
+fn is_up_to_date(path: &Path, control: &Path) -> Result<bool, ZsyncError> {
+    if !control.is_file() {
+        return Ok(false);
+    }
+    let existing = match MetaFile::from_reader(BufReader::new(File::open(control)?)) {
+        Ok(existing) => existing,
+        Err(_) => return Ok(false),
+    };
+
+    let metadata = fs::metadata(path)?;
+    let modified: DateTime<Utc> = metadata.modified()?.into();
+    Ok(existing.length as u64 == metadata.len()
+        && existing.m_time.timestamp() == modified.timestamp())
+}
+
+/// XXH3-128 of every block of a file, kept beside its control file so that
+/// [`ZsyncMaker::make_incremental`] can tell which blocks are unchanged.
+///
+/// The truncated checksums in the control file are too short to rule out a
+/// changed block, so reuse is only ever based on these. They are much
+/// cheaper to compute than the MD4 they save.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockDigests {
+    /// `SHA-1` of the file, tying the digests to its control file.
+    pub sha1: String,
+    pub blocksize: usize,
+    pub digests: Vec<[u8; 16]>,
+}
+
+impl BlockDigests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads `SHA-1` and `Blocksize` header lines, a blank line, then the
+    /// digests back to back.
+    pub fn from_reader(mut reader: impl BufRead) -> Result<BlockDigests, ZsyncError> {
+        let mut digests = BlockDigests::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end_matches('\n');
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = line
+                .split_once(": ")
+                .ok_or_else(|| ZsyncError::MalformedHeader(line.to_string()))?;
+            match name {
+                "SHA-1" => digests.sha1 = value.to_string(),
+                "Blocksize" => {
+                    digests.blocksize = value.parse().map_err(|_| ZsyncError::BadHeader {
+                        name: name.to_string(),
+                        value: value.to_string(),
+                    })?
+                }
+                _ => return Err(ZsyncError::MalformedHeader(line.to_string())),
+            }
+        }
+
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw)?;
+        if raw.len() % 16 != 0 {
+            return Err(ZsyncError::TruncatedChecksums {
+                expected: raw.len().next_multiple_of(16),
+                actual: raw.len(),
+            });
+        }
+        digests.digests = raw
+            .chunks_exact(16)
+            .map(|digest| digest.try_into().unwrap())
+            .collect();
+        Ok(digests)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BlockDigests, ZsyncError> {
+        BlockDigests::from_reader(bytes)
+    }
+
+    pub fn load(path: &Path) -> Result<BlockDigests, ZsyncError> {
+        BlockDigests::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn write(&self, mut w: impl Write) -> Result<(), ZsyncError> {
+        write!(w, "SHA-1: {}\nBlocksize: {}\n\n", self.sha1, self.blocksize)?;
+        for digest in &self.digests {
+            w.write_all(digest)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // Writing to a Vec cannot fail.
+        self.write(&mut buf).unwrap();
+        buf
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ZsyncError> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+}
+
+// Digests of the blocks hashed so far, plus the digest and strong checksum
+// of each block of an earlier version of the file.
+struct Digesting {
+    previous: Vec<([u8; 16], Vec<u8>)>,
+    digests: Vec<[u8; 16]>,
+}
+
+// Hashes every block in `reader`, also returning the number of bytes read.
+// With `digesting`, strong checksums of blocks whose digest is unchanged are
+// taken from the earlier version.
+fn hash_blocks(
+    mut reader: impl Read,
+    blocksize: usize,
+    mut sha1: Option<&mut Sha1>,
+    mut digesting: Option<&mut Digesting>,
+    cancel: Option<&CancellationToken>,
+) -> Result<(BlockSums, usize), ZsyncError> {
+    let mut config = Configuration::new();
+    config.block_length = blocksize as i32;
+    config.strong_sum_length = MAX_CHECKSUM_BYTES as i32;
+    let mut gen = Generator::new(config);
+
+    let mut block = vec![0u8; blocksize];
+    let mut sums = Vec::new();
+    let mut length = 0;
+
+    loop {
+        if let Some(token) = cancel {
+            token.check()?;
+        }
+        let n = read_block(&mut reader, &mut block)?;
+        if n == 0 {
+            break;
+        }
+        length += n;
+        if let Some(sha1) = sha1.as_mut() {
+            sha1.update(&block[..n]);
+        }
+
+        let mut reused = None;
+        if let Some(digesting) = digesting.as_mut() {
+            let digest = xxh3_128(&block[..n]).to_be_bytes();
+            reused = digesting
+                .previous
+                .get(sums.len())
+                .filter(|(previous, _)| *previous == digest)
+                .map(|(_, strong)| strong.clone());
+            digesting.digests.push(digest);
+        }
+
+        // The last block is hashed zero-padded to the full blocksize.
+        for b in block.iter_mut().skip(n) {
+            *b = 0;
+        }
+
+        let weak = gen.generate_weak_sum(&block, 0);
+        let strong = reused.unwrap_or_else(|| gen.generate_strong_sum(&block, 0, blocksize));
+        sums.push((weak, strong));
+
+        if n < blocksize {
+            break;
+        }
+    }
+
+    Ok((sums, length))
+}
+
+fn hash_sha1(
+    mut reader: impl Read,
+    sha1: &mut Sha1,
+    cancel: Option<&CancellationToken>,
+) -> Result<usize, ZsyncError> {
+    let mut buf = vec![0u8; 65536];
+    let mut length = 0;
+    loop {
+        if let Some(token) = cancel {
+            token.check()?;
+        }
+        let n = read_block(&mut reader, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        length += n;
+        sha1.update(&buf[..n]);
+    }
+    Ok(length)
+}
+
+impl Default for ZsyncMaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Blocksize `zsyncmake` picks for a file of `length` bytes.
+pub fn auto_blocksize(length: u64) -> usize {
+    if length < 100_000_000 {
+        2048
+    } else {
+        4096
+    }
+}
+
+/// `Hash-Lengths` (seq_matches, rsum bytes, checksum bytes) `zsyncmake` picks
+/// to keep the odds of a false block match low for this length and blocksize.
+pub fn auto_hash_lengths(length: u64, blocksize: usize) -> (u32, u32, u32) {
+    let blocks = (1 + length / blocksize as u64) as f64;
+    let log_len = (length.max(1) as f64).log2();
+    let seq_matches: u32 = if length > blocksize as u64 { 2 } else { 1 };
+
+    let rsum_bytes = ((log_len + (blocksize as f64).log2() - 8.6) / seq_matches as f64 / 8.0)
+        .ceil()
+        .clamp(2.0, 4.0) as u32;
+
+    let checksum_bytes = ((20.0 + log_len + blocks.log2()) / seq_matches as f64 / 8.0).ceil();
+    let checksum_bytes2 = ((7.9 + (20.0 + blocks.log2())) / 8.0).floor();
+    let checksum_bytes = checksum_bytes.max(checksum_bytes2).clamp(3.0, 16.0) as u32;
+
+    (seq_matches, rsum_bytes, checksum_bytes)
+}